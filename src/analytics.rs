@@ -0,0 +1,28 @@
+use worker::{AnalyticsEngineDataPointBuilder, Env};
+
+/// One request as written to the `ANALYTICS` dataset.
+///
+/// Blobs are `outcome`, `host` and `content_type`; doubles are `size` and `latency_ms`.
+/// The host doubles as the index so that queries can sample per origin.
+pub(crate) struct DataPoint<'a> {
+    pub(crate) outcome: &'a str,
+    pub(crate) host: &'a str,
+    pub(crate) content_type: &'a str,
+    pub(crate) size: u64,
+    pub(crate) latency_ms: u64,
+}
+
+/// Writes `point` to the `ANALYTICS` dataset, doing nothing when it is not bound.
+pub(crate) fn write(env: &Env, point: DataPoint) {
+    let Ok(dataset) = env.analytics_engine("ANALYTICS") else {
+        return;
+    };
+    let result = AnalyticsEngineDataPointBuilder::new()
+        .indexes([point.host])
+        .blobs([point.outcome, point.host, point.content_type])
+        .doubles([point.size as f64, point.latency_ms as f64])
+        .write_to(&dataset);
+    if let Err(e) = result {
+        tracing::warn!(error = %e, "failed to write analytics data point");
+    }
+}
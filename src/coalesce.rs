@@ -0,0 +1,133 @@
+use std::{
+    cell::{Cell, RefCell},
+    time::Duration,
+};
+
+use futures::{
+    channel::oneshot,
+    future::{self, Either},
+};
+use worker::{
+    durable_object, Date, Delay, DurableObject, Env, Request, Response, Result, State, Stub,
+};
+// `#[durable_object]` expands to `wasm_bindgen` paths, which would otherwise need the
+// crate as a direct dependency
+use worker::wasm_bindgen;
+
+/// Default time a follower waits for the leader before fetching the origin itself.
+const DEFAULT_TIMEOUT_MS: u64 = 10_000;
+
+fn timeout_ms(env: &Env) -> u64 {
+    env.var("COALESCE_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .unwrap_or(DEFAULT_TIMEOUT_MS)
+}
+
+/// Hands out a per-key lease so only one request fetches an uncached URL at a time.
+///
+/// One instance exists per R2 key. The first caller of `/acquire` becomes the leader and
+/// fetches the origin; later callers are held until the leader calls `/release`, after
+/// which they read the freshly stored object from R2. A lease older than the coalesce
+/// timeout is considered abandoned and handed to the next caller. `/try-acquire` answers
+/// `busy` instead of waiting, for callers with nothing to gain from the leader's result.
+#[durable_object]
+pub struct FetchLock {
+    env: Env,
+    leader_since: Cell<Option<u64>>,
+    waiters: RefCell<Vec<oneshot::Sender<()>>>,
+}
+
+impl DurableObject for FetchLock {
+    fn new(_state: State, env: Env) -> Self {
+        Self {
+            env,
+            leader_since: Cell::new(None),
+            waiters: RefCell::new(Vec::new()),
+        }
+    }
+
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        match req.path().as_str() {
+            path @ ("/acquire" | "/try-acquire") => {
+                let now = Date::now().as_millis();
+                let abandoned = self
+                    .leader_since
+                    .get()
+                    .is_none_or(|since| now.saturating_sub(since) > timeout_ms(&self.env));
+                if abandoned {
+                    self.leader_since.set(Some(now));
+                    return Response::ok("leader");
+                }
+                if path == "/try-acquire" {
+                    return Response::ok("busy");
+                }
+                let (tx, rx) = oneshot::channel();
+                self.waiters.borrow_mut().push(tx);
+                let _ = rx.await;
+                Response::ok("follower")
+            }
+            "/release" => {
+                self.leader_since.set(None);
+                for waiter in self.waiters.take() {
+                    let _ = waiter.send(());
+                }
+                Response::empty()
+            }
+            _ => Response::error("Not Found", 404),
+        }
+    }
+}
+
+/// The outcome of asking the coordinator for the right to fetch a key.
+pub(crate) enum Lease {
+    /// This request must fetch the origin and then call [`release`].
+    Leader(Stub),
+    /// Another request fetched the origin while this one waited.
+    Follower,
+    /// No coordinator is bound, or it did not answer in time.
+    Unavailable,
+}
+
+pub(crate) async fn acquire(env: &Env, key: &str) -> Result<Lease> {
+    let Ok(namespace) = env.durable_object("FETCH_LOCK") else {
+        return Ok(Lease::Unavailable);
+    };
+    let stub = namespace.get_by_name(key)?;
+    let timeout = Delay::from(Duration::from_millis(timeout_ms(env)));
+    let acquire = stub.fetch_with_str("https://fetch-lock/acquire");
+    let mut res = match future::select(Box::pin(acquire), timeout).await {
+        Either::Left((res, _)) => res?,
+        Either::Right(_) => {
+            tracing::warn!(key = key, "timed out waiting for in-flight fetch");
+            return Ok(Lease::Unavailable);
+        }
+    };
+    match res.text().await?.as_str() {
+        "leader" => Ok(Lease::Leader(stub)),
+        _ => Ok(Lease::Follower),
+    }
+}
+
+/// Takes the lease for `key` only if nobody holds it, without waiting.
+///
+/// Returns [`Lease::Follower`] when another request is already fetching the key. As the
+/// caller did not wait, nothing has necessarily been stored yet.
+pub(crate) async fn try_acquire(env: &Env, key: &str) -> Result<Lease> {
+    let Ok(namespace) = env.durable_object("FETCH_LOCK") else {
+        return Ok(Lease::Unavailable);
+    };
+    let stub = namespace.get_by_name(key)?;
+    let mut res = stub
+        .fetch_with_str("https://fetch-lock/try-acquire")
+        .await?;
+    match res.text().await?.as_str() {
+        "leader" => Ok(Lease::Leader(stub)),
+        _ => Ok(Lease::Follower),
+    }
+}
+
+pub(crate) async fn release(stub: &Stub) -> Result<()> {
+    stub.fetch_with_str("https://fetch-lock/release").await?;
+    Ok(())
+}
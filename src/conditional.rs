@@ -0,0 +1,161 @@
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+
+/// Evaluates a request's `If-None-Match`/`If-Modified-Since` against a representation's
+/// validators, returning `true` when a `304 Not Modified` should be sent.
+///
+/// As in RFC 9110, `If-Modified-Since` is ignored whenever `If-None-Match` is present.
+pub(crate) fn is_not_modified(
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> bool {
+    if let Some(if_none_match) = if_none_match {
+        let Some(etag) = etag else {
+            return false;
+        };
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || opaque_tag(candidate) == opaque_tag(etag));
+    }
+    match (if_modified_since, last_modified) {
+        (Some(since), Some(last_modified)) => {
+            match (
+                OffsetDateTime::parse(since.trim(), &Rfc2822),
+                OffsetDateTime::parse(last_modified.trim(), &Rfc2822),
+            ) {
+                (Ok(since), Ok(last_modified)) => last_modified <= since,
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Strips the weak indicator so tags compare with the weak comparison function.
+fn opaque_tag(tag: &str) -> &str {
+    tag.trim().trim_start_matches("W/")
+}
+
+/// Evaluates an `If-Range` header against a representation's validators, returning
+/// `true` when the requested range may be served and `false` when the whole body should
+/// be sent instead.
+///
+/// As RFC 9110 requires, entity tags use the strong comparison function, so weak tags
+/// never match, and a date must equal `Last-Modified` exactly.
+pub(crate) fn if_range_matches(
+    if_range: &str,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return etag.is_some_and(|etag| !if_range.starts_with("W/") && if_range == etag.trim());
+    }
+    match last_modified {
+        Some(last_modified) => match (
+            OffsetDateTime::parse(if_range, &Rfc2822),
+            OffsetDateTime::parse(last_modified.trim(), &Rfc2822),
+        ) {
+            (Ok(date), Ok(last_modified)) => date == last_modified,
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAST_MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+    #[test]
+    fn matching_etag_is_not_modified() {
+        let etag = Some("\"abc\"");
+        assert!(is_not_modified(Some("\"abc\""), None, etag, None));
+        assert!(is_not_modified(Some("\"x\", \"abc\""), None, etag, None));
+        assert!(is_not_modified(Some("*"), None, etag, None));
+        // If-None-Match compares weakly
+        assert!(is_not_modified(Some("W/\"abc\""), None, etag, None));
+        assert!(is_not_modified(
+            Some("\"abc\""),
+            None,
+            Some("W/\"abc\""),
+            None
+        ));
+    }
+
+    #[test]
+    fn other_etags_are_modified() {
+        assert!(!is_not_modified(
+            Some("\"other\""),
+            None,
+            Some("\"abc\""),
+            None
+        ));
+        assert!(!is_not_modified(Some("\"abc\""), None, None, None));
+    }
+
+    #[test]
+    fn if_none_match_overrides_if_modified_since() {
+        let since = Some(LAST_MODIFIED);
+        let last_modified = Some(LAST_MODIFIED);
+        assert!(!is_not_modified(
+            Some("\"other\""),
+            since,
+            Some("\"abc\""),
+            last_modified,
+        ));
+    }
+
+    #[test]
+    fn unchanged_since_date_is_not_modified() {
+        let last_modified = Some(LAST_MODIFIED);
+        assert!(is_not_modified(
+            None,
+            Some(LAST_MODIFIED),
+            None,
+            last_modified
+        ));
+        assert!(is_not_modified(
+            None,
+            Some("Thu, 22 Oct 2015 07:28:00 GMT"),
+            None,
+            last_modified,
+        ));
+    }
+
+    #[test]
+    fn changed_or_unparsable_dates_are_modified() {
+        let last_modified = Some(LAST_MODIFIED);
+        assert!(!is_not_modified(
+            None,
+            Some("Tue, 20 Oct 2015 07:28:00 GMT"),
+            None,
+            last_modified,
+        ));
+        assert!(!is_not_modified(
+            None,
+            Some("yesterday"),
+            None,
+            last_modified
+        ));
+        assert!(!is_not_modified(None, Some(LAST_MODIFIED), None, None));
+        assert!(!is_not_modified(None, None, Some("\"abc\""), last_modified));
+    }
+
+    #[test]
+    fn if_range_uses_strong_comparison() {
+        assert!(if_range_matches("\"abc\"", Some("\"abc\""), None));
+        assert!(!if_range_matches("W/\"abc\"", Some("W/\"abc\""), None));
+        assert!(!if_range_matches("\"abc\"", Some("\"other\""), None));
+        assert!(if_range_matches(LAST_MODIFIED, None, Some(LAST_MODIFIED)));
+        assert!(!if_range_matches(
+            "Thu, 22 Oct 2015 07:28:00 GMT",
+            None,
+            Some(LAST_MODIFIED),
+        ));
+    }
+}
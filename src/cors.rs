@@ -0,0 +1,34 @@
+use worker::{Headers, Result};
+
+pub(crate) const ALLOWED_METHODS: &str = "GET, HEAD, POST, DELETE, OPTIONS";
+
+pub(crate) const ALLOWED_HEADERS: &str =
+    "Content-Type, Range, If-None-Match, If-Modified-Since, Cache-Control, X-Prefer-Cache, Authorization, X-Cache-Tag";
+
+/// Returns `origin` if it may read responses cross-origin.
+///
+/// The origin is echoed back instead of answering with `*`, so authenticated responses are
+/// only exposed to origins that were explicitly allowed. `*` in the allowlist matches any
+/// origin.
+pub(crate) fn allowed_origin<'a>(origin: &'a str, allowed_origins: &[String]) -> Option<&'a str> {
+    allowed_origins
+        .iter()
+        .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+        .then_some(origin)
+}
+
+/// Adds CORS headers for `origin` to `headers`, leaving them untouched for other origins.
+pub(crate) fn apply(headers: &Headers, origin: &str, allowed_origins: &[String]) -> Result<()> {
+    let Some(origin) = allowed_origin(origin, allowed_origins) else {
+        return Ok(());
+    };
+    headers.set("Access-Control-Allow-Origin", origin)?;
+    headers.set("Access-Control-Allow-Methods", ALLOWED_METHODS)?;
+    headers.set("Access-Control-Allow-Headers", ALLOWED_HEADERS)?;
+    headers.set(
+        "Access-Control-Expose-Headers",
+        "Content-Length, Content-Range, ETag, X-Cache",
+    )?;
+    headers.append("Vary", "Origin")?;
+    Ok(())
+}
@@ -0,0 +1,21 @@
+/// Whether `host` may be fetched under the `ALLOWED_DOMAINS`/`BLOCKED_DOMAINS` lists.
+///
+/// The blocklist wins over the allowlist, and an empty allowlist allows every host.
+pub(crate) fn is_permitted(host: &str, allowed: &[String], blocked: &[String]) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if blocked.iter().any(|pattern| matches(&host, pattern)) {
+        return false;
+    }
+    allowed.is_empty() || allowed.iter().any(|pattern| matches(&host, pattern))
+}
+
+/// Matches `host` against `example.com` exactly or `*.example.com` for any subdomain.
+pub(crate) fn matches(host: &str, pattern: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .strip_suffix(suffix)
+            .is_some_and(|rest| rest.ends_with('.')),
+        None => host == pattern,
+    }
+}
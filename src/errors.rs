@@ -0,0 +1,124 @@
+use worker::{Headers, Response, Result};
+
+use crate::negotiate;
+
+#[derive(serde::Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+    code: &'a str,
+}
+
+/// Builds an error response with `status`. Clients whose `Accept` lists
+/// `application/json` get `{"error": message, "code": code}`; others get `message` as
+/// plain text, as before JSON errors existed.
+pub(crate) fn respond(
+    headers: &Headers,
+    status: u16,
+    code: &str,
+    message: impl AsRef<str>,
+) -> Result<Response> {
+    let message = message.as_ref();
+    let accept = headers.get("Accept")?;
+    if !negotiate::accepts(accept.as_deref(), "application/json") {
+        return Response::error(message, status);
+    }
+    Ok(Response::from_json(&ErrorBody {
+        error: message,
+        code,
+    })?
+    .with_status(status))
+}
+
+pub(crate) fn bad_request(
+    headers: &Headers,
+    code: &str,
+    message: impl AsRef<str>,
+) -> Result<Response> {
+    respond(headers, 400, code, message)
+}
+
+pub(crate) fn forbidden(
+    headers: &Headers,
+    code: &str,
+    message: impl AsRef<str>,
+) -> Result<Response> {
+    respond(headers, 403, code, message)
+}
+
+pub(crate) fn not_found(
+    headers: &Headers,
+    code: &str,
+    message: impl AsRef<str>,
+) -> Result<Response> {
+    respond(headers, 404, code, message)
+}
+
+pub(crate) fn payload_too_large(
+    headers: &Headers,
+    code: &str,
+    message: impl AsRef<str>,
+) -> Result<Response> {
+    respond(headers, 413, code, message)
+}
+
+pub(crate) fn bad_gateway(
+    headers: &Headers,
+    code: &str,
+    message: impl AsRef<str>,
+) -> Result<Response> {
+    respond(headers, 502, code, message)
+}
+
+/// Whether an origin that answered `origin_status`, or could not be fetched at all, may
+/// well succeed on a retry. Such failures are a `502` with `Retry-After`; definite answers
+/// such as `404` are passed on as they are.
+pub(crate) fn is_transient_origin_failure(origin_status: Option<u16>) -> bool {
+    origin_status.is_none_or(|status| status == 429 || status >= 500)
+}
+
+/// Whether `status` is a `3xx`, which reaches the fallback without a body worth passing on.
+pub(crate) fn is_redirection(status: u16) -> bool {
+    (300..400).contains(&status)
+}
+
+/// `403` for a missing or wrong `access_token`, the most common error of all.
+pub(crate) fn invalid_token(headers: &Headers) -> Result<Response> {
+    forbidden(headers, "INVALID_TOKEN", "invalid access token")
+}
+
+/// `400` for a required query parameter that was not given.
+pub(crate) fn missing_param(headers: &Headers, name: &str) -> Result<Response> {
+    bad_request(
+        headers,
+        "MISSING_PARAMETER",
+        format!("missing {name} parameter"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unreachable_and_failing_origins_are_transient() {
+        assert!(is_transient_origin_failure(None));
+        for status in [429, 500, 502, 503, 504] {
+            assert!(is_transient_origin_failure(Some(status)), "{status}");
+        }
+    }
+
+    #[test]
+    fn redirections_are_recognized() {
+        assert!(is_redirection(304));
+        assert!(is_redirection(301));
+        assert!(!is_redirection(200));
+        assert!(!is_redirection(404));
+    }
+
+    #[test]
+    fn missing_images_are_not_transient() {
+        for status in [400, 401, 403, 404, 410] {
+            assert!(!is_transient_origin_failure(Some(status)), "{status}");
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use worker::Url;
+
+/// Extracts the image URLs referenced by an RSS or Atom document, in document order and
+/// without duplicates.
+///
+/// This is a tag scanner rather than an XML parser: entities are decoded first so that
+/// `<img>` tags inside escaped `<description>`/`<content>` HTML are found too. It picks up
+/// `<img src>`, image `<enclosure>`s, image `<media:content>` and `<media:thumbnail>`.
+/// Relative URLs are resolved against `base`.
+pub(crate) fn image_urls(document: &str, base: &Url) -> Vec<String> {
+    let document = decode_entities(document);
+    let mut urls: Vec<String> = Vec::new();
+    let mut rest = document.as_str();
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = rest.find('>').unwrap_or(rest.len());
+        let tag = &rest[..end];
+        rest = &rest[end..];
+        let Some(url) = image_url(tag) else {
+            continue;
+        };
+        let Ok(url) = base.join(url.trim()) else {
+            continue;
+        };
+        let url = url.to_string();
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    urls
+}
+
+/// The image URL referenced by the inside of a tag, e.g. `img src="a.png"`.
+fn image_url(tag: &str) -> Option<&str> {
+    let name_end = tag
+        .find(|c: char| c.is_ascii_whitespace() || c == '/')
+        .unwrap_or(tag.len());
+    let name = tag[..name_end].to_ascii_lowercase();
+    let attrs = &tag[name_end..];
+    let is_image_type = || attr(attrs, "type").is_some_and(|t| t.starts_with("image/"));
+    match name.as_str() {
+        "img" => attr(attrs, "src"),
+        "enclosure" if is_image_type() => attr(attrs, "url"),
+        "media:content" if is_image_type() || attr(attrs, "medium") == Some("image") => {
+            attr(attrs, "url")
+        }
+        "media:thumbnail" => attr(attrs, "url"),
+        _ => None,
+    }
+}
+
+/// The value of a quoted attribute `name` in `attrs`.
+fn attr<'a>(attrs: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = attrs;
+    loop {
+        let eq = rest.find('=')?;
+        let key = rest[..eq].trim().rsplit(char::is_whitespace).next()?;
+        let value = rest[eq + 1..].trim_start();
+        let quote = value.chars().next()?;
+        if quote != '"' && quote != '\'' {
+            let end = value.find(char::is_whitespace).unwrap_or(value.len());
+            if key.eq_ignore_ascii_case(name) {
+                return Some(&value[..end]);
+            }
+            rest = &value[end..];
+            continue;
+        }
+        let end = value[1..].find(quote)?;
+        if key.eq_ignore_ascii_case(name) {
+            return Some(&value[1..end + 1]);
+        }
+        rest = &value[end + 2..];
+    }
+}
+
+/// Decodes the XML and common HTML entities, leaving unknown ones untouched.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';').filter(|&end| end <= 10) else {
+            decoded.push('&');
+            rest = &rest[1..];
+            continue;
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "amp" => Some('&'),
+            _ => entity
+                .strip_prefix("#x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
@@ -0,0 +1,117 @@
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+
+/// Whether `Cache-Control` has `no-store` or `no-cache`.
+pub(crate) fn forbids_caching(cache_control: Option<&str>) -> bool {
+    cache_control.is_some_and(|cache_control| {
+        cache_control
+            .split(',')
+            .map(|directive| directive.trim().to_ascii_lowercase())
+            .any(|directive| directive == "no-store" || directive == "no-cache")
+    })
+}
+
+/// Derives a TTL in seconds from the origin's `Cache-Control` and `Expires` headers.
+///
+/// `no-store`/`no-cache` mean the object must always be revalidated, `max-age` wins over
+/// `Expires`, and `None` means the origin expressed no caching preference.
+pub(crate) fn origin_ttl(
+    cache_control: Option<&str>,
+    expires: Option<&str>,
+    now: u64,
+) -> Option<u64> {
+    if forbids_caching(cache_control) {
+        return Some(0);
+    }
+    if let Some(cache_control) = cache_control {
+        let directives: Vec<String> = cache_control
+            .split(',')
+            .map(|directive| directive.trim().to_ascii_lowercase())
+            .collect();
+        let max_age = directives
+            .iter()
+            .filter_map(|directive| directive.strip_prefix("max-age="))
+            .find_map(|max_age| max_age.trim_matches('"').parse().ok());
+        if max_age.is_some() {
+            return max_age;
+        }
+    }
+    let expires = expires?;
+    // an invalid Expires value means "already expired" (RFC 9111, section 5.3)
+    let Ok(expires) = OffsetDateTime::parse(expires, &Rfc2822) else {
+        return Some(0);
+    };
+    Some(u64::try_from(expires.unix_timestamp()).map_or(0, |ts| ts.saturating_sub(now)))
+}
+
+/// The `Age` of a copy stored at `stored_at`, in seconds.
+pub(crate) fn age(stored_at: u64, now: u64) -> u64 {
+    now.saturating_sub(stored_at)
+}
+
+/// The `max-age` left for a copy expiring at `expires_at`, zero once it is stale.
+pub(crate) fn remaining_ttl(expires_at: u64, now: u64) -> u64 {
+    expires_at.saturating_sub(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn age_grows_with_time_since_stored() {
+        assert_eq!(age(NOW, NOW), 0);
+        assert_eq!(age(NOW - 90, NOW), 90);
+        assert!(age(NOW - 90, NOW + 10) > age(NOW - 90, NOW));
+        // a clock behind the one that stored it never yields a negative age
+        assert_eq!(age(NOW + 5, NOW), 0);
+    }
+
+    #[test]
+    fn max_age_is_the_remaining_ttl() {
+        assert_eq!(remaining_ttl(NOW + 3600, NOW), 3600);
+        assert_eq!(remaining_ttl(NOW + 3600, NOW + 600), 3000);
+        assert_eq!(remaining_ttl(NOW, NOW), 0);
+        assert_eq!(remaining_ttl(NOW - 1, NOW), 0);
+    }
+
+    #[test]
+    fn max_age_wins_over_expires() {
+        let expires = Some("Wed, 21 Oct 2015 07:28:00 GMT");
+        assert_eq!(
+            origin_ttl(Some("public, max-age=600"), expires, NOW),
+            Some(600)
+        );
+        assert_eq!(origin_ttl(Some("MAX-AGE=\"60\""), None, NOW), Some(60));
+    }
+
+    #[test]
+    fn expires_is_relative_to_now() {
+        // 2023-11-14T22:13:20Z
+        assert_eq!(
+            origin_ttl(None, Some("Tue, 14 Nov 2023 23:13:20 GMT"), NOW),
+            Some(3600),
+        );
+        assert_eq!(
+            origin_ttl(None, Some("Tue, 14 Nov 2023 21:13:20 GMT"), NOW),
+            Some(0),
+        );
+        assert_eq!(origin_ttl(None, Some("0"), NOW), Some(0));
+    }
+
+    #[test]
+    fn no_store_and_no_cache_forbid_caching() {
+        assert!(forbids_caching(Some("no-store")));
+        assert!(forbids_caching(Some("public, No-Cache")));
+        assert!(!forbids_caching(Some("public, max-age=60")));
+        assert!(!forbids_caching(None));
+        assert_eq!(origin_ttl(Some("no-store, max-age=60"), None, NOW), Some(0));
+    }
+
+    #[test]
+    fn no_preference_is_none() {
+        assert_eq!(origin_ttl(None, None, NOW), None);
+        assert_eq!(origin_ttl(Some("public"), None, NOW), None);
+    }
+}
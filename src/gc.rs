@@ -0,0 +1,149 @@
+use worker::{Bucket, Include, Object, Result};
+
+use crate::{is_read_only, now_secs, r2, Shared};
+
+/// Custom metadata field holding the unix time an object was last served.
+pub(crate) const LAST_ACCESSED_FIELD: &str = "last_accessed";
+
+/// Custom metadata field that, set to `true`, exempts an object from [`collect`].
+pub(crate) const PINNED_FIELD: &str = "pinned";
+
+/// How stale `last_accessed` may get before a hit rewrites it. Updating metadata means
+/// re-uploading the object, so this is kept coarse.
+const TOUCH_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+/// Objects requested per list call; also the most `delete_multiple` accepts.
+const PAGE_SIZE: u32 = 1000;
+
+/// Pages scanned by one [`collect`] call before handing back a cursor, to stay within
+/// the Worker's subrequest and CPU limits.
+const MAX_PAGES_PER_RUN: usize = 5;
+
+#[derive(Debug, Default, serde::Serialize)]
+pub(crate) struct Summary {
+    scanned: usize,
+    deleted: usize,
+    /// Objects old enough to delete but kept because they are pinned.
+    pinned: usize,
+    /// Where the next run should resume; absent once the whole bucket has been scanned.
+    cursor: Option<String>,
+}
+
+/// When `obj` was last served, falling back to its upload time for objects stored before
+/// access times were recorded.
+pub(crate) fn last_accessed(obj: &Object) -> Result<u64> {
+    Ok(obj
+        .custom_metadata()?
+        .get(LAST_ACCESSED_FIELD)
+        .and_then(|last_accessed| last_accessed.parse().ok())
+        .unwrap_or_else(|| obj.uploaded().as_millis() / 1000))
+}
+
+/// Records a cache hit on `obj` after the response has been sent, at most once per
+/// [`TOUCH_INTERVAL_SECS`].
+pub(crate) fn touch_in_background(ctx: &Shared, obj: &Object) -> Result<()> {
+    if is_read_only(&ctx.env) {
+        return Ok(());
+    }
+    let now = now_secs();
+    if now.saturating_sub(last_accessed(obj)?) < TOUCH_INTERVAL_SECS {
+        return Ok(());
+    }
+    let ctx = ctx.clone();
+    let key = obj.key();
+    ctx.data.clone().wait_until(async move {
+        let touched = set_metadata_field(&ctx, &key, LAST_ACCESSED_FIELD, Some(now.to_string()));
+        if let Err(e) = touched.await {
+            tracing::warn!(key = key, error = %e, "failed to record last access");
+        }
+    });
+    Ok(())
+}
+
+/// Rewrites the object at `key`, in whichever tier holds it, with metadata `field` set
+/// to `value` or removed. Returns whether the object exists.
+pub(crate) async fn set_metadata_field(
+    ctx: &Shared,
+    key: &str,
+    field: &str,
+    value: Option<String>,
+) -> Result<bool> {
+    let Some((bucket, obj)) = ctx.get_any_tier(key).await? else {
+        return Ok(false);
+    };
+    let Some(body) = obj.body() else {
+        return Ok(false);
+    };
+    let mut metadata = obj.custom_metadata()?;
+    match value {
+        Some(value) => metadata.insert(field.to_string(), value),
+        None => metadata.remove(field),
+    };
+    // a newer copy stored meanwhile wins over this metadata change
+    if !r2::put_if_match(
+        bucket,
+        key,
+        r2::restream(body, obj.size())?,
+        obj.http_metadata(),
+        metadata,
+        &obj.etag(),
+    )
+    .await?
+    {
+        tracing::info!(
+            key = key,
+            field = field,
+            "object changed in R2, skipping update"
+        );
+    }
+    Ok(true)
+}
+
+/// Deletes objects under `prefix` not served for `max_age` seconds, resuming from
+/// `cursor`.
+pub(crate) async fn collect(
+    bucket: &Bucket,
+    prefix: Option<String>,
+    mut cursor: Option<String>,
+    max_age: u64,
+) -> Result<Summary> {
+    let cutoff = now_secs().saturating_sub(max_age);
+    let mut summary = Summary::default();
+    for _ in 0..MAX_PAGES_PER_RUN {
+        let mut list = bucket
+            .list()
+            .limit(PAGE_SIZE)
+            .include(vec![Include::CustomMetadata]);
+        if let Some(prefix) = &prefix {
+            list = list.prefix(prefix.clone());
+        }
+        if let Some(cursor) = cursor.take() {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        let objects = page.objects();
+        summary.scanned += objects.len();
+        let mut expired = Vec::new();
+        for obj in &objects {
+            let pinned =
+                obj.custom_metadata()?.get(PINNED_FIELD).map(String::as_str) == Some("true");
+            if last_accessed(obj)? < cutoff {
+                if pinned {
+                    summary.pinned += 1;
+                } else {
+                    expired.push(obj.key());
+                }
+            }
+        }
+        if !expired.is_empty() {
+            summary.deleted += expired.len();
+            bucket.delete_multiple(expired).await?;
+        }
+        if !page.truncated() {
+            return Ok(summary);
+        }
+        cursor = page.cursor();
+    }
+    summary.cursor = cursor;
+    Ok(summary)
+}
@@ -1,140 +1,4774 @@
-use std::collections::HashMap;
+mod analytics;
+mod coalesce;
+mod compress;
+mod conditional;
+mod cors;
+mod domains;
+mod errors;
+mod feed;
+mod freshness;
+mod gc;
+mod limiter;
+mod memory;
+mod mirrors;
+mod negotiate;
+mod normalize;
+mod placeholder;
+mod queue;
+mod r2;
+mod range;
+mod ratelimit;
+mod sampling;
+mod signing;
+mod sniff;
+mod ssrf;
+mod stats;
+mod svg;
+mod transcode;
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+    str::FromStr,
+    sync::Once,
+    time::Duration,
+};
+
+use base64::{
+    alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
+use futures::{
+    future::{self, Either},
+    StreamExt,
+};
 
 use sha2::{Digest, Sha256};
+use tracing::Instrument;
+use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::{
     fmt::{format::Pretty, time::UtcTime},
     prelude::*,
 };
 use tracing_web::{performance_layer, MakeWebConsoleWriter};
+use worker::Range as R2Range;
 use worker::{
-    event, Context, Data, Env, Error, Fetch, Headers, Object, Request, Response,
-    Result, RouteContext, Router, Url,
+    event, AbortController, Bucket, ByteStream, Cache, Context, Data, Date, Delay, EncodeBody, Env,
+    Error, Fetch, FixedLengthStream, Headers, HttpMetadata, Include, MessageBatch, MessageExt,
+    Object, Request, Response, Result, RouteContext, Router, Stub, Url,
 };
 
-fn get_r2_key(url: &str) -> String {
-    let hash = Sha256::digest(url.as_bytes());
-    let elen = base16ct::encoded_len(&hash);
+const HOT_TIER: &str = "hot";
+
+/// Tier of objects above `COLD_THRESHOLD_BYTES`, kept in `R2_COLD_BINDING`.
+const COLD_TIER: &str = "cold";
+
+/// The parts of a [`RouteContext`] the cache needs, detached from the router so that
+/// background tasks can own a copy.
+#[derive(Clone)]
+struct Shared {
+    env: Env,
+    data: Rc<Context>,
+}
+
+impl From<RouteContext<Rc<Context>>> for Shared {
+    fn from(route: RouteContext<Rc<Context>>) -> Self {
+        Self {
+            env: route.env,
+            data: route.data,
+        }
+    }
+}
+
+impl Shared {
+    fn bucket(&self, binding: &str) -> Result<Bucket> {
+        self.env.bucket(binding)
+    }
+
+    /// The buckets objects may be stored in, by tier, the hot one first. The cold tier is
+    /// only there when `R2_COLD_BINDING` is bound.
+    fn buckets(&self) -> Result<Vec<(&'static str, Bucket)>> {
+        let mut buckets = vec![(HOT_TIER, self.bucket("R2_BINDING")?)];
+        if let Ok(cold) = self.bucket("R2_COLD_BINDING") {
+            buckets.push((COLD_TIER, cold));
+        }
+        Ok(buckets)
+    }
+
+    /// The bucket of `tier`, as recorded in the `tier` metadata of stored objects.
+    fn tier_bucket(&self, tier: &str) -> Result<Bucket> {
+        match tier {
+            COLD_TIER => self.bucket("R2_COLD_BINDING"),
+            _ => self.bucket("R2_BINDING"),
+        }
+    }
+
+    /// Reads `key` from whichever tier holds it, along with that tier's bucket.
+    async fn get_any_tier(&self, key: &str) -> Result<Option<(Bucket, Object)>> {
+        for (_, bucket) in self.buckets()? {
+            if let Some(obj) = bucket.get(key).execute().await? {
+                return Ok(Some((bucket, obj)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The bucket `obj` was read from.
+    fn bucket_of(&self, obj: &Object) -> Result<Bucket> {
+        let tier = obj.custom_metadata()?.remove("tier");
+        self.tier_bucket(tier.as_deref().unwrap_or(HOT_TIER))
+    }
+}
+
+/// Reads a comma-separated env var, falling back to `default` when unset.
+fn var_list(env: &Env, name: &str, default: &str) -> Vec<String> {
+    env.var(name)
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parses an optional env var, failing loudly on a malformed value.
+fn var_parse<T: FromStr>(env: &Env, name: &str) -> Result<Option<T>> {
+    let Ok(value) = env.var(name) else {
+        return Ok(None);
+    };
+    value
+        .to_string()
+        .trim()
+        .parse()
+        .map(Some)
+        .map_err(|_| Error::from(format!("invalid {name}")))
+}
+
+fn now_secs() -> u64 {
+    Date::now().as_millis() / 1000
+}
+
+/// Whether the stored `expires_at` of `obj` has passed. Objects without one never expire.
+fn is_expired(obj: &Object) -> Result<bool> {
+    Ok(expires_at(obj)?.is_some_and(|expires_at| now_secs() >= expires_at))
+}
+
+/// When the body of `obj` was fetched from the origin, as recorded in `stored_at`, or its
+/// upload time for objects stored before that was recorded. Unlike the upload time, this
+/// survives metadata-only rewrites.
+fn stored_at(obj: &Object) -> Result<u64> {
+    Ok(obj
+        .custom_metadata()?
+        .get("stored_at")
+        .and_then(|stored_at| stored_at.parse().ok())
+        .unwrap_or_else(|| obj.uploaded().as_millis() / 1000))
+}
+
+fn expires_at(obj: &Object) -> Result<Option<u64>> {
+    Ok(obj
+        .custom_metadata()?
+        .get("expires_at")
+        .and_then(|expires_at| expires_at.parse().ok()))
+}
+
+/// Whether an expired `obj` may still be served while it is refreshed in the background,
+/// i.e. it expired less than `STALE_WHILE_REVALIDATE_SECONDS` ago.
+fn is_within_stale_window(ctx: &Shared, obj: &Object) -> Result<bool> {
+    let Some(window) = var_parse::<u64>(&ctx.env, "STALE_WHILE_REVALIDATE_SECONDS")? else {
+        return Ok(false);
+    };
+    Ok(expires_at(obj)?.is_some_and(|expires_at| now_secs() < expires_at + window))
+}
+
+/// The TTL an object is stored with: `FORCE_TTL_SECONDS` when set, otherwise the
+/// origin's, falling back to `DEFAULT_TTL_SECONDS`.
+fn ttl_for(ctx: &Shared, headers: &Headers, now: u64) -> Result<Option<u64>> {
+    let cache_control = headers.get("Cache-Control")?;
+    if let Some(ttl) = var_parse::<u64>(&ctx.env, "FORCE_TTL_SECONDS")? {
+        if freshness::forbids_caching(cache_control.as_deref()) {
+            tracing::info!(
+                cache_control = cache_control.as_deref().unwrap_or_default(),
+                ttl = ttl,
+                "overriding origin no-store/no-cache with FORCE_TTL_SECONDS",
+            );
+        }
+        return Ok(Some(ttl));
+    }
+    let origin_ttl = freshness::origin_ttl(
+        cache_control.as_deref(),
+        headers.get("Expires")?.as_deref(),
+        now,
+    );
+    if origin_ttl.is_some() {
+        return Ok(origin_ttl);
+    }
+    var_parse(&ctx.env, "DEFAULT_TTL_SECONDS")
+}
+
+/// Spreads `ttl` randomly within +/- `TTL_JITTER_PERCENT` of itself, so objects cached
+/// together do not all expire, and hit the origin, at the same moment.
+fn jittered_ttl(ctx: &Shared, ttl: u64) -> Result<u64> {
+    let percent: f64 = var_parse(&ctx.env, "TTL_JITTER_PERCENT")?.unwrap_or(0.0);
+    let percent = percent.clamp(0.0, 100.0);
+    if percent == 0.0 || ttl == 0 {
+        return Ok(ttl);
+    }
+    Ok(jitter(ttl, percent, worker::js_sys::Math::random()))
+}
+
+/// `ttl` moved by up to `percent` of itself, downwards for a `random` below 0.5 and
+/// upwards above it.
+fn jitter(ttl: u64, percent: f64, random: f64) -> u64 {
+    let offset = ttl as f64 * percent / 100.0 * (2.0 * random - 1.0);
+    (ttl as f64 + offset).round().max(0.0) as u64
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let elen = base16ct::encoded_len(bytes);
     let mut dst = vec![0u8; elen];
-    let hex = base16ct::lower::encode_str(&hash, &mut dst).expect("dst length is correct");
-    format!("{}/{}/{}", &hex[0..2], &hex[2..4], &hex[4..])
+    base16ct::lower::encode_str(bytes, &mut dst)
+        .expect("dst length is correct")
+        .to_string()
 }
 
-async fn put_in_r2(ctx: &RouteContext<()>, url: &str, body: Vec<u8>) -> Result<()> {
-    let key = get_r2_key(url);
-    let bucket = ctx.bucket("R2_BINDING")?;
-    let r = bucket.head(&key).await?;
-    if r.is_some() {
-        tracing::info!(
-            url = url,
-            key = key,
-            "object already exists in R2, skipping put",
-        );
+fn sha256_hex(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes))
+}
+
+/// Hashes a normalized URL for its R2 key with the `KEY_HASH` algorithm: `sha256` (the
+/// default), `blake3`, or the non-cryptographic 128-bit `xxh3`.
+fn key_hash_hex(env: &Env, bytes: &[u8]) -> String {
+    let algorithm = env.var("KEY_HASH").map(|v| v.to_string()).ok();
+    hash_hex(algorithm.as_deref(), bytes)
+}
+
+/// Hashes `bytes` with `algorithm`, SHA-256 when it is unset or unknown.
+fn hash_hex(algorithm: Option<&str>, bytes: &[u8]) -> String {
+    match algorithm {
+        Some("blake3") => hex(blake3::hash(bytes).as_bytes()),
+        Some("xxh3") => hex(&xxhash_rust::xxh3::xxh3_128(bytes).to_be_bytes()),
+        Some("sha256") | None => sha256_hex(bytes),
+        Some(other) => {
+            tracing::warn!(key_hash = other, "unknown KEY_HASH, using sha256");
+            sha256_hex(bytes)
+        }
+    }
+}
+
+/// Shard widths used when `KEY_SHARD_LEVELS` is unset, giving `ab/cd/rest`.
+const DEFAULT_KEY_SHARD_LEVELS: &[usize] = &[2, 2];
+
+/// Most hex digits `KEY_SHARD_LEVELS` may spend on directories, leaving at least as many
+/// for the shortest hash.
+const MAX_KEY_SHARD_DIGITS: usize = 16;
+
+/// The widths of the directory segments in front of the hash: `KEY_SHARD_LEVELS` as a
+/// comma-separated list such as `2,2` or `3`, with `0` or an empty value for flat keys.
+fn key_shard_levels(env: &Env) -> Vec<usize> {
+    match env.var("KEY_SHARD_LEVELS") {
+        Ok(levels) => parse_shard_levels(&levels.to_string()),
+        Err(_) => DEFAULT_KEY_SHARD_LEVELS.to_vec(),
+    }
+}
+
+/// Parses a `KEY_SHARD_LEVELS` value, falling back to the default when it is invalid.
+fn parse_shard_levels(levels: &str) -> Vec<usize> {
+    let parsed: std::result::Result<Vec<usize>, _> = levels
+        .split(',')
+        .map(str::trim)
+        .filter(|level| !level.is_empty())
+        .map(str::parse)
+        .collect();
+    match parsed {
+        Ok(widths) if widths.iter().sum::<usize>() <= MAX_KEY_SHARD_DIGITS => {
+            widths.into_iter().filter(|&width| width > 0).collect()
+        }
+        _ => {
+            tracing::warn!(
+                key_shard_levels = levels,
+                "invalid KEY_SHARD_LEVELS, using 2,2"
+            );
+            DEFAULT_KEY_SHARD_LEVELS.to_vec()
+        }
+    }
+}
+
+/// `url` normalized the way it is before hashing into an R2 key.
+fn normalized_url(env: &Env, url: &str) -> String {
+    let strip = var_list(
+        env,
+        "STRIP_QUERY_PARAMS",
+        normalize::DEFAULT_STRIP_QUERY_PARAMS,
+    );
+    normalize::normalize_url(url, &strip)
+}
+
+/// The image URL inside `url` when it is wrapped in a proxy URL matching
+/// `UNWRAP_PREFIX`, otherwise `url` itself.
+fn unwrapped_url(env: &Env, url: &str) -> String {
+    let prefixes = var_list(env, "UNWRAP_PREFIX", "");
+    let unwrapped = normalize::unwrap(url, &prefixes);
+    if unwrapped != url {
+        tracing::info!(url = url, unwrapped = unwrapped, "unwrapped proxied url");
+    }
+    unwrapped
+}
+
+/// `url` without its `CACHE_VERSION_PARAM`, which only distinguishes cache keys and is
+/// never sent to the origin or stored.
+fn origin_url(env: &Env, url: &str) -> String {
+    match env.var("CACHE_VERSION_PARAM") {
+        Ok(param) => normalize::strip_param(url, &param.to_string()),
+        Err(_) => url.to_string(),
+    }
+}
+
+fn get_r2_key(env: &Env, url: &str) -> String {
+    let url = normalized_url(env, url);
+    let key = sharded(&key_hash_hex(env, url.as_bytes()), &key_shard_levels(env));
+    let key = if includes_host(env) {
+        format!("{}/{key}", host_slug(&url_host(&url)))
+    } else {
+        key
+    };
+    match key_namespace(env) {
+        Some(namespace) => format!("{namespace}/{key}"),
+        None => key,
+    }
+}
+
+/// `hex` split into directory segments of the given `widths`, e.g. `ab/cd/rest` for `2,2`.
+fn sharded(hex: &str, widths: &[usize]) -> String {
+    let mut key = String::new();
+    let mut rest = hex;
+    for &width in widths {
+        let (segment, tail) = rest.split_at(width);
+        key.push_str(segment);
+        key.push('/');
+        rest = tail;
+    }
+    key.push_str(rest);
+    key
+}
+
+/// Whether `KEY_INCLUDE_HOST` puts the origin host in front of the hashed part of keys.
+fn includes_host(env: &Env) -> bool {
+    env.var("KEY_INCLUDE_HOST")
+        .is_ok_and(|value| value.to_string().eq_ignore_ascii_case("true"))
+}
+
+/// `host` as a key segment, e.g. `cdn-example-com` for `cdn.example.com`.
+fn host_slug(host: &str) -> String {
+    let slug: String = host
+        .trim_end_matches('.')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let slug = slug.trim_matches('-');
+    if slug.is_empty() {
+        "unknown-host".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+/// `KEY_NAMESPACE` without surrounding slashes, if set to anything but slashes.
+fn key_namespace(env: &Env) -> Option<String> {
+    let namespace = env.var("KEY_NAMESPACE").ok()?.to_string();
+    let namespace = namespace.trim_matches('/');
+    (!namespace.is_empty()).then(|| namespace.to_string())
+}
+
+/// The key of the variant of `url` negotiated for `format`.
+fn format_r2_key(env: &Env, url: &str, format: &str) -> String {
+    format!("{}.{format}", get_r2_key(env, url))
+}
+
+/// The key of the `format` derivative of `url` transcoded by this worker. It is kept apart
+/// from the negotiated variants, which hold what the origin itself sent.
+fn derived_r2_key(env: &Env, url: &str, format: &str) -> String {
+    format!("{}.derived.{format}", get_r2_key(env, url))
+}
+
+/// The format bucket the client's `Accept` header selects, if any.
+fn negotiated_format(headers: &Headers) -> Result<Option<&'static str>> {
+    Ok(negotiate::format_bucket(headers.get("Accept")?.as_deref()))
+}
+
+/// Every key `url` may be stored under, for any client.
+fn all_r2_keys(env: &Env, url: &str) -> Vec<String> {
+    let mut keys = vec![get_r2_key(env, url)];
+    for (format, _) in negotiate::FORMATS {
+        keys.push(format_r2_key(env, url, format));
+    }
+    keys.push(derived_r2_key(env, url, "webp"));
+    keys
+}
+
+/// The `TRANSCODE_TO` format to derive for this client, if transcoding is enabled and
+/// the client's `Accept` lists it. Only WebP can be encoded; AVIF is refused.
+fn transcode_target(env: &Env, headers: &Headers) -> Result<Option<&'static str>> {
+    let Ok(target) = env.var("TRANSCODE_TO") else {
+        return Ok(None);
+    };
+    match target.to_string().as_str() {
+        "webp" if negotiate::accepts(headers.get("Accept")?.as_deref(), "image/webp") => {
+            Ok(Some("webp"))
+        }
+        "webp" | "off" => Ok(None),
+        "avif" => {
+            tracing::warn!("AVIF encoding is not available, ignoring TRANSCODE_TO=avif");
+            Ok(None)
+        }
+        target => {
+            tracing::warn!(target = target, "unknown TRANSCODE_TO, not transcoding");
+            Ok(None)
+        }
+    }
+}
+
+/// The keys `url` may be stored under for this client, the negotiated variant first, then
+/// any transcoded derivative.
+fn candidate_r2_keys(env: &Env, url: &str, headers: &Headers) -> Result<Vec<String>> {
+    let mut keys = Vec::with_capacity(3);
+    let negotiated = negotiated_format(headers)?;
+    if let Some(format) = negotiated {
+        keys.push(format_r2_key(env, url, format));
+    }
+    if let Some(target) = transcode_target(env, headers)? {
+        keys.push(derived_r2_key(env, url, target));
+    }
+    keys.push(get_r2_key(env, url));
+    Ok(keys)
+}
+
+/// Origin response headers forwarded to the client; everything else, including
+/// `Set-Cookie` and hop-by-hop headers, is dropped.
+const FORWARDED_HEADERS: &[&str] = &[
+    "Content-Type",
+    "Content-Length",
+    "Content-Range",
+    "Accept-Ranges",
+    "Last-Modified",
+    "ETag",
+    "Cache-Control",
+    "Content-Disposition",
+];
+
+/// Origin response headers persisted in R2 custom metadata and replayed on cache hits,
+/// keyed by metadata field. `Content-Type` is handled separately since it may be sniffed.
+pub(crate) const METADATA_HEADERS: &[(&str, &str)] = &[
+    ("last_modified", "Last-Modified"),
+    ("etag", "ETag"),
+    ("cache_control", "Cache-Control"),
+    ("content_disposition", "Content-Disposition"),
+];
+
+/// Origin response headers besides [`METADATA_HEADERS`] that a put reads, to pick the key
+/// and TTL and to record the type and encoding.
+pub(crate) const PUT_HEADERS: &[&str] = &["Content-Type", "Content-Encoding", "Vary", "Expires"];
+
+/// Metadata derived from the stored body itself, exposed on cache hits and `HEAD`.
+const DERIVED_HEADERS: &[(&str, &str)] = &[
+    ("width", "X-Image-Width"),
+    ("height", "X-Image-Height"),
+    ("blurhash", "X-Blurhash"),
+    ("animated", "X-Image-Animated"),
+];
+
+fn with_forwarded_headers(res: Response) -> Result<Response> {
+    let headers = Headers::new();
+    for name in FORWARDED_HEADERS {
+        if let Some(value) = res.headers().get(name)? {
+            headers.set(name, &sanitize_header_value(&value))?;
+        }
+    }
+    Ok(res.with_headers(headers))
+}
+
+/// The custom metadata fields recording the sanitized [`METADATA_HEADERS`] that `header`
+/// finds in an origin response.
+fn header_metadata(
+    header: impl Fn(&str) -> Result<Option<String>>,
+) -> Result<Vec<(String, String)>> {
+    let mut metadata = Vec::new();
+    for (field, name) in METADATA_HEADERS {
+        if let Some(value) = header(name)? {
+            metadata.push((field.to_string(), sanitize_header_value(&value)));
+        }
+    }
+    Ok(metadata)
+}
+
+/// The [`METADATA_HEADERS`] and [`DERIVED_HEADERS`] recorded in an object's `metadata`,
+/// as they are replayed on cache hits.
+fn replayed_headers(
+    metadata: &HashMap<String, String>,
+) -> impl Iterator<Item = (&'static str, &String)> {
+    METADATA_HEADERS
+        .iter()
+        .chain(DERIVED_HEADERS)
+        .filter_map(|(field, header)| Some((*header, metadata.get(*field)?)))
+}
+
+/// Strips control characters, CR and LF among them, so a hostile origin cannot smuggle
+/// extra header lines through a replayed value.
+fn sanitize_header_value(value: &str) -> String {
+    value.chars().filter(|c| !c.is_control()).collect()
+}
+
+/// The ETag of a stored object: the one recorded at put time, or for objects stored before
+/// that or streamed in without one, a weak one from R2's own content hash, which stays the
+/// same as long as the body does.
+fn object_etag(obj: &Object) -> Result<String> {
+    Ok(match obj.custom_metadata()?.remove("etag") {
+        Some(etag) => etag,
+        None => format!("W/{}", obj.http_etag()),
+    })
+}
+
+/// A strong ETag derived from the body, for origins that do not send one.
+fn content_etag(body: &[u8]) -> String {
+    format!("\"{}\"", sha256_hex(body))
+}
+
+/// Builds a `304 Not Modified` when the request's validators match `etag`/`last_modified`.
+fn not_modified(
+    headers: &Headers,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> Result<Option<Response>> {
+    let matched = conditional::is_not_modified(
+        headers.get("If-None-Match")?.as_deref(),
+        headers.get("If-Modified-Since")?.as_deref(),
+        etag,
+        last_modified,
+    );
+    if !matched {
+        return Ok(None);
+    }
+    let res = Response::empty()?.with_status(304);
+    if let Some(etag) = etag {
+        res.headers().set("ETag", etag)?;
+    }
+    if let Some(last_modified) = last_modified {
+        res.headers().set("Last-Modified", last_modified)?;
+    }
+    Ok(Some(res))
+}
+
+/// Whether `READ_ONLY` suspends every write to R2 made while serving requests: new
+/// objects, refreshed TTLs, access times and negative cache entries. Cached objects are
+/// still served and origins still proxied.
+fn is_read_only(env: &Env) -> bool {
+    env.var("READ_ONLY")
+        .is_ok_and(|value| value.to_string().eq_ignore_ascii_case("true"))
+}
+
+/// What a write to R2 carries over from the request beyond the origin response.
+#[derive(Clone, Default)]
+pub(crate) struct PutOptions {
+    /// Replaces an unexpired object instead of keeping it.
+    pub(crate) overwrite: bool,
+    /// TTL in seconds stored instead of the one derived from the origin's headers.
+    pub(crate) ttl: Option<u64>,
+    /// The client's `X-Cache-Tag`.
+    pub(crate) tag: Option<String>,
+    /// Stores a transcoded derivative under its own key rather than the variant's.
+    pub(crate) derived: bool,
+    /// Stores under this key instead of the one derived from the URL, for `GET /selftest`,
+    /// which also writes under `READ_ONLY`.
+    pub(crate) key: Option<String>,
+}
+
+/// Custom metadata field holding the client's `X-Cache-Tag`.
+const TAG_FIELD: &str = "tag";
+
+/// Longest `X-Cache-Tag` kept, as custom metadata is limited to 2 KB in total.
+const MAX_TAG_BYTES: usize = 256;
+
+/// The sanitized `X-Cache-Tag` of a client request, if it sent a non-empty one.
+fn cache_tag(headers: &Headers) -> Result<Option<String>> {
+    let Some(tag) = headers.get("X-Cache-Tag")? else {
+        return Ok(None);
+    };
+    let mut tag = sanitize_header_value(tag.trim());
+    if tag.len() > MAX_TAG_BYTES {
+        let mut end = MAX_TAG_BYTES;
+        while !tag.is_char_boundary(end) {
+            end -= 1;
+        }
+        tag.truncate(end);
+    }
+    Ok((!tag.is_empty()).then_some(tag))
+}
+
+/// Stores `body` for `url`, under the variant key for `format` when the origin negotiated
+/// the response on `Accept`.
+///
+/// The body is taken fully buffered: the ETag, integrity hash, dimensions and placeholder
+/// are all derived from it. Large origin bodies that need none of that go through
+/// [`put_stream_in_r2`] instead.
+async fn put_in_r2(
+    ctx: &Shared,
+    url: &str,
+    format: Option<&str>,
+    headers: &Headers,
+    body: Vec<u8>,
+    options: &PutOptions,
+) -> Result<()> {
+    let Some(mut pending) = prepare_put(ctx, url, format, headers, options).await? else {
         return Ok(());
+    };
+    let metadata = &mut pending.metadata;
+    let svg_sanitized =
+        sanitizes_svg(ctx, metadata.get("content_type").map(String::as_str), &body)?;
+    let body = if svg_sanitized {
+        svg::sanitize(&body)
+    } else {
+        body
+    };
+    if svg_sanitized {
+        metadata.insert("svg_sanitized".to_string(), "true".to_string());
     }
-    let value = Data::Bytes(body);
-    let _ = bucket
-        .put(&key, value)
-        .custom_metadata(HashMap::from([("url".to_string(), url.to_string())]))
-        .execute()
+    metadata
+        .entry("etag".to_string())
+        .or_insert_with(|| content_etag(&body));
+    metadata.insert("content_length".to_string(), body.len().to_string());
+    if let Some((width, height)) = sniff::image_dimensions(&body) {
+        metadata.insert("width".to_string(), width.to_string());
+        metadata.insert("height".to_string(), height.to_string());
+    }
+    if sniff::is_animated(&body) {
+        metadata.insert("animated".to_string(), "true".to_string());
+    }
+    if var_parse::<bool>(&ctx.env, "GENERATE_LQIP")?.unwrap_or(false) {
+        match placeholder::blurhash(&body) {
+            Some(blurhash) => {
+                metadata.insert("blurhash".to_string(), blurhash);
+            }
+            None => tracing::info!(url = url, "could not decode image for placeholder"),
+        }
+    }
+    let compressible = metadata
+        .get("content_type")
+        .is_some_and(|content_type| compress::is_compressible(content_type));
+    let body = if compressible {
+        metadata.insert("content_encoding".to_string(), "gzip".to_string());
+        compress::gzip(&body).map_err(|e| Error::from(format!("gzip failed: {e}")))?
+    } else {
+        body
+    };
+    // the hash covers the stored bytes, so integrity checks need not decompress
+    metadata.insert("content_sha256".to_string(), sha256_hex(&body));
+    let length = body.len() as u64;
+    write_put(ctx, url, pending, Data::Bytes(body), length).await
+}
+
+/// Streams an origin `body` of `length` bytes into R2 without buffering it, for
+/// [`streamed_put_length`] responses.
+///
+/// Nothing is derived from the body: the ETag falls back to R2's own, and no integrity
+/// hash, dimensions or placeholder are recorded.
+///
+/// That the body is never read into the isolate rests on it only ever being handed on as
+/// a stream; the native tests cannot run Workers streams to measure it.
+async fn put_stream_in_r2(
+    ctx: &Shared,
+    url: &str,
+    format: Option<&str>,
+    headers: &Headers,
+    body: ByteStream,
+    length: u64,
+    options: &PutOptions,
+) -> Result<()> {
+    // dropping `body` when the put is skipped cancels its branch of the tee
+    let Some(mut pending) = prepare_put(ctx, url, format, headers, options).await? else {
+        return Ok(());
+    };
+    pending
+        .metadata
+        .insert("content_length".to_string(), length.to_string());
+    let body = Data::Stream(FixedLengthStream::wrap(body, length));
+    write_put(ctx, url, pending, body, length).await
+}
+
+/// Streams `body` into R2 after the response has been sent. Unlike
+/// [`put_in_r2_in_background`] this never goes through `CACHE_QUEUE`, whose messages would
+/// have to carry the whole body.
+fn put_stream_in_r2_in_background(
+    ctx: &Shared,
+    url: &str,
+    format: Option<&'static str>,
+    headers: &Headers,
+    body: ByteStream,
+    length: u64,
+    opts: &RequestOptions,
+) {
+    let options = opts.put_options();
+    let lease = opts.lease.take();
+    let ctx = ctx.clone();
+    let url = url.to_string();
+    let headers = headers.clone();
+    ctx.data.clone().wait_until(async move {
+        let put = put_stream_in_r2(&ctx, &url, format, &headers, body, length, &options);
+        if let Err(e) = put.await {
+            tracing::warn!(url = url, error = %e, "R2 write failed, response was not cached");
+        }
+        if let Some(stub) = lease {
+            release_lease(&url, &stub).await;
+        }
+    });
+}
+
+/// A write to R2 that is going ahead, with the metadata that does not depend on the body.
+struct PendingPut {
+    key: String,
+    /// Etags seen per tier, so the write can be made conditional on them.
+    etags: HashMap<&'static str, String>,
+    metadata: HashMap<String, String>,
+}
+
+/// Picks the key for `url` and collects what carries over from an existing copy, or
+/// returns `None` when the write should be skipped.
+async fn prepare_put(
+    ctx: &Shared,
+    url: &str,
+    format: Option<&str>,
+    headers: &Headers,
+    options: &PutOptions,
+) -> Result<Option<PendingPut>> {
+    if is_read_only(&ctx.env) && options.key.is_none() {
+        tracing::info!(url = url, "READ_ONLY is set, skipping put");
+        return Ok(None);
+    }
+    let key = match (&options.key, format) {
+        (Some(key), _) => key.clone(),
+        (None, Some(format)) if options.derived => derived_r2_key(&ctx.env, url, format),
+        (None, Some(format)) if negotiate::varies_on_accept(headers.get("Vary")?.as_deref()) => {
+            format_r2_key(&ctx.env, url, format)
+        }
+        _ => get_r2_key(&ctx.env, url),
+    };
+    let mut pinned = None;
+    let mut ttl_override = options.ttl;
+    let mut tag = options.tag.clone();
+    let mut etags = HashMap::new();
+    for (tier, bucket) in ctx.buckets()? {
+        if let Some(existing) = bucket.head(&key).await? {
+            if !options.overwrite && !is_expired(&existing)? {
+                tracing::info!(
+                    url = url,
+                    key = key,
+                    "object already exists in R2, skipping put",
+                );
+                return Ok(None);
+            }
+            etags.insert(tier, existing.etag());
+            let mut existing = existing.custom_metadata()?;
+            pinned = existing.remove(gc::PINNED_FIELD);
+            ttl_override = ttl_override.or_else(|| {
+                existing
+                    .get("ttl_override")
+                    .and_then(|ttl| ttl.parse().ok())
+            });
+            tag = tag.or_else(|| existing.remove(TAG_FIELD));
+        }
+    }
+    let mut metadata = HashMap::from([("url".to_string(), origin_url(&ctx.env, url))]);
+    // a refetched copy stays pinned
+    if let Some(pinned) = pinned {
+        metadata.insert(gc::PINNED_FIELD.to_string(), pinned);
+    }
+    if let Some(tag) = tag {
+        metadata.insert(TAG_FIELD.to_string(), tag);
+    }
+    if let Some(content_type) = headers.get("Content-Type")? {
+        metadata.insert("content_type".to_string(), content_type);
+    }
+    metadata.extend(header_metadata(|name| headers.get(name))?);
+    // the body was decoded on the way in, so this is only recorded, never replayed
+    if let Some(encoding) = headers.get("Content-Encoding")? {
+        metadata.insert(
+            "origin_content_encoding".to_string(),
+            sanitize_header_value(&encoding),
+        );
+    }
+    let now = now_secs();
+    if let Some(ttl) = ttl_override {
+        // kept so that refetches and revalidations honor it too
+        metadata.insert("ttl_override".to_string(), ttl.to_string());
+    }
+    let ttl = match ttl_override {
+        Some(ttl) => Some(ttl),
+        None => ttl_for(ctx, headers, now)?
+            .map(|ttl| jittered_ttl(ctx, ttl))
+            .transpose()?,
+    };
+    if let Some(ttl) = ttl {
+        metadata.insert("expires_at".to_string(), (now + ttl).to_string());
+    }
+    metadata.insert("stored_at".to_string(), now.to_string());
+    metadata.insert(gc::LAST_ACCESSED_FIELD.to_string(), now.to_string());
+    Ok(Some(PendingPut {
+        key,
+        etags,
+        metadata,
+    }))
+}
+
+/// Writes the `length` bytes of `body` for a [`prepare_put`] write to the tier its size
+/// puts it in.
+async fn write_put(
+    ctx: &Shared,
+    url: &str,
+    pending: PendingPut,
+    body: Data,
+    length: u64,
+) -> Result<()> {
+    let PendingPut {
+        key,
+        etags,
+        mut metadata,
+    } = pending;
+    let buckets = ctx.buckets()?;
+    let cold_threshold: Option<u64> = var_parse(&ctx.env, "COLD_THRESHOLD_BYTES")?;
+    let tier = match cold_threshold {
+        Some(threshold) if buckets.len() > 1 && length > threshold => COLD_TIER,
+        _ => HOT_TIER,
+    };
+    metadata.insert("tier".to_string(), tier.to_string());
+    // only read by R2's public endpoint, for `SERVE_MODE=redirect`
+    let http_metadata = HttpMetadata {
+        content_type: metadata.get("content_type").cloned(),
+        ..Default::default()
+    };
+    let bucket = ctx.tier_bucket(tier)?;
+    match etags.get(tier) {
+        // another isolate may have stored a newer copy since the head above; keep it
+        Some(etag) => {
+            if !r2::put_if_match(bucket, &key, body, http_metadata, metadata, etag).await? {
+                tracing::info!(
+                    url = url,
+                    key = key,
+                    "object changed in R2 since it was checked, skipping put",
+                );
+                return Ok(());
+            }
+        }
+        None => {
+            let _ = bucket
+                .put(&key, body)
+                .http_metadata(http_metadata)
+                .custom_metadata(metadata)
+                .execute()
+                .await?;
+        }
+    }
+    // reads try the hot tier first, so an older copy in another tier would shadow this one
+    for (_, bucket) in buckets.iter().filter(|(other, _)| *other != tier) {
+        bucket.delete(&key).await?;
+    }
+    // the image is back, so a not-found sentinel must not hide it
+    ctx.bucket("R2_BINDING")?
+        .delete(negative_r2_key(&ctx.env, url))
         .await?;
     Ok(())
 }
 
-async fn get_from_r2(ctx: &RouteContext<()>, url: &str) -> Result<Option<Object>> {
-    let key = get_r2_key(url);
-    let bucket = ctx.bucket("R2_BINDING")?;
-    bucket.get(&key).execute().await
+async fn head_from_r2(ctx: &Shared, url: &str, headers: &Headers) -> Result<Option<Object>> {
+    for key in candidate_r2_keys(&ctx.env, url, headers)? {
+        for (_, bucket) in ctx.buckets()? {
+            if let Some(obj) = bucket.head(&key).await? {
+                return Ok(Some(obj));
+            }
+        }
+    }
+    Ok(None)
 }
 
-async fn cache_url(ctx: &RouteContext<()>, url_str: &str, headers: &Headers) -> Result<Response> {
-    let h = Headers::new();
-    h.set("User-Agent", &headers.get("User-Agent")?.unwrap_or("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/142.0.0.0 Safari/537.36".into()))?;
-    let req = Request::new_with_init(
-        url_str,
-        &worker::RequestInit {
-            headers: h,
-            method: worker::Method::Get,
-            ..Default::default()
-        },
-    )?;
-    let mut res = Fetch::Request(req).send().await?;
-    match res.status_code() {
-        200..300 => {
-            put_in_r2(ctx, url_str, res.cloned()?.bytes().await?).await?;
-            Ok(res)
+/// The key of the sentinel recording that the origin did not have `url`.
+fn negative_r2_key(env: &Env, url: &str) -> String {
+    format!("{}.negative", get_r2_key(env, url))
+}
+
+/// Whether the origin answered `404`/`410` for `url` within `NEGATIVE_TTL_SECONDS`.
+async fn is_negatively_cached(ctx: &Shared, url: &str) -> bool {
+    let result: Result<bool> = async {
+        if var_parse::<u64>(&ctx.env, "NEGATIVE_TTL_SECONDS")?.is_none() {
+            return Ok(false);
         }
-        400.. => {
-            if let Some(obj) = get_from_r2(ctx, url_str).await? {
-                if let Some(body) = obj.body() {
-                    tracing::info!(
-                        url = url_str,
-                        key = obj.key(),
-                        "object found in R2, returning cached response",
-                    );
-                    return Response::from_body(body.response_body()?);
-                }
-            }
-            tracing::warn!(
-                url = url_str,
-                status = res.status_code(),
-                body = res.text().await.unwrap_or_default(),
-                "object not found in R2, returning fallback response",
-            );
-            let fallback_url = ctx.env.var("FALLBACK_URL")?.to_string();
-            let url = Url::parse(&fallback_url)?;
-            Fetch::Url(url).send().await
+        let bucket = ctx.bucket("R2_BINDING")?;
+        match bucket.head(negative_r2_key(&ctx.env, url)).await? {
+            Some(sentinel) => Ok(!is_expired(&sentinel)?),
+            None => Ok(false),
         }
-        _ => Err(Error::from("unexpected status code from origin")),
     }
+    .await;
+    result.unwrap_or_else(|e| {
+        tracing::warn!(url = url, error = %e, "R2 read failed, ignoring negative cache");
+        false
+    })
 }
 
-#[tracing::instrument(err, skip(ctx))]
-async fn get(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let q = req
-        .url()?
-        .query_pairs()
-        .find(|(k, _)| k == "url")
-        .map(|(_, v)| v.into_owned());
-    let url = q.ok_or_else(|| Error::from("missing url parameter"))?;
-    cache_url(&ctx, &url, req.headers()).await
+/// Records a `404`/`410` for `url` for `NEGATIVE_TTL_SECONDS`, after the response has been
+/// sent. Does nothing when negative caching is disabled.
+fn cache_negative_in_background(ctx: &Shared, url: &str, status: u16) -> Result<()> {
+    let Some(ttl) = var_parse::<u64>(&ctx.env, "NEGATIVE_TTL_SECONDS")? else {
+        return Ok(());
+    };
+    if is_read_only(&ctx.env) {
+        return Ok(());
+    }
+    let key = negative_r2_key(&ctx.env, url);
+    let metadata = HashMap::from([
+        ("url".to_string(), origin_url(&ctx.env, url)),
+        ("status".to_string(), status.to_string()),
+        ("expires_at".to_string(), (now_secs() + ttl).to_string()),
+    ]);
+    let bucket = ctx.bucket("R2_BINDING")?;
+    let url = url.to_string();
+    ctx.data.wait_until(async move {
+        let put = bucket.put(&key, Data::Empty).custom_metadata(metadata);
+        if let Err(e) = put.execute().await {
+            tracing::warn!(url = url, error = %e, "failed to record negative cache entry");
+        }
+    });
+    Ok(())
 }
 
-#[derive(serde::Deserialize)]
-struct PostRequest {
-    url: String,
-    access_token: String,
+/// Runs [`put_in_r2`] after the response has been sent, so that a slow or failing R2
+/// write never holds up or fails the client's request.
+///
+/// With a `CACHE_QUEUE` producer bound, the write is enqueued instead and performed by
+/// the `queue` consumer, which retries it when it fails.
+///
+/// The request's fetch lease is released once the object is in R2, so that the requests waiting on
+/// it find it there. Such writes are never enqueued, as they would only land after the
+/// waiters had given up on R2 and fetched the origin themselves.
+fn put_in_r2_in_background(
+    ctx: &Shared,
+    url: &str,
+    format: Option<&'static str>,
+    headers: &Headers,
+    body: Vec<u8>,
+    opts: &RequestOptions,
+) {
+    let options = opts.put_options();
+    let lease = opts.lease.take();
+    let ctx = ctx.clone();
+    let url = url.to_string();
+    let headers = headers.clone();
+    ctx.data.clone().wait_until(async move {
+        // checked here too so that no queue message is sent for a write that would be skipped
+        if lease.is_none() && !is_read_only(&ctx.env) {
+            match queue::enqueue(&ctx.env, &url, format, &headers, &body, &options).await {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(e) => tracing::warn!(url = url, error = %e, "enqueueing R2 write failed, writing directly"),
+            }
+        }
+        if let Err(e) = put_in_r2(&ctx, &url, format, &headers, body, &options).await {
+            tracing::warn!(url = url, error = %e, "R2 write failed, response was not cached");
+        }
+        if let Some(stub) = lease {
+            release_lease(&url, &stub).await;
+        }
+    });
 }
 
-#[tracing::instrument(err, skip(ctx))]
-async fn post(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let body: PostRequest = req.json().await?;
-    let api_token = ctx.env.var("API_TOKEN")?.to_string();
-    if body.access_token != api_token {
-        return Response::error("invalid access token", 403);
+/// Releases the fetch lock of `url`. Waiters fetch the origin themselves once the lease
+/// times out, so a failure is only logged.
+async fn release_lease(url: &str, stub: &Stub) {
+    if let Err(e) = coalesce::release(stub).await {
+        tracing::warn!(url = url, error = %e, "failed to release fetch lock");
     }
-    cache_url(&ctx, &body.url, req.headers()).await?;
-    Response::empty()
 }
 
-#[event(start)]
-fn start() {
-    let fmt_layer = tracing_subscriber::fmt::layer()
-        .json()
-        .with_ansi(false)
-        .with_timer(UtcTime::rfc_3339())
-        .with_writer(MakeWebConsoleWriter::default());
-    let perf_layer = performance_layer().with_details_from_fields(Pretty::default());
-    tracing_subscriber::registry()
-        .with(fmt_layer)
-        .with(perf_layer)
-        .init();
+/// Stores a `target` derivative of the origin `body` alongside the original after the
+/// response has been sent. Nothing is stored when transcoding fails or does not shrink it,
+/// so such clients keep getting the original.
+fn transcode_in_background(
+    ctx: &Shared,
+    url: &str,
+    target: &'static str,
+    headers: &Headers,
+    body: Vec<u8>,
+    options: PutOptions,
+) -> Result<()> {
+    // the derivative could not be stored, so it is not worth the CPU
+    if is_read_only(&ctx.env) {
+        return Ok(());
+    }
+    let quality = var_parse::<u8>(&ctx.env, "TRANSCODE_QUALITY")?
+        .unwrap_or(transcode::DEFAULT_QUALITY)
+        .min(100);
+    let options = PutOptions {
+        derived: true,
+        ..options
+    };
+    // the derivative is its own representation: the origin's validators do not describe it
+    let headers = headers.clone();
+    headers.set("Content-Type", "image/webp")?;
+    headers.set("Vary", "Accept")?;
+    headers.delete("ETag")?;
+    let ctx = ctx.clone();
+    let url = url.to_string();
+    ctx.data.clone().wait_until(async move {
+        let Some(derived) = transcode::to_webp(&body, quality) else {
+            tracing::info!(url = url, "image not transcoded, serving the original");
+            return;
+        };
+        tracing::info!(
+            url = url,
+            original = body.len(),
+            transcoded = derived.len(),
+            "storing transcoded derivative",
+        );
+        let put = put_in_r2(&ctx, &url, Some(target), &headers, derived, &options);
+        if let Err(e) = put.await {
+            tracing::warn!(url = url, error = %e, "R2 write of transcoded image failed");
+        }
+    });
+    Ok(())
+}
+
+/// Reads the cached copy of `url`. R2 being unavailable is treated as a miss so that the
+/// origin can still be served.
+async fn get_from_r2(ctx: &Shared, url: &str, headers: &Headers) -> Result<Option<Object>> {
+    let result: Result<Option<Object>> = async {
+        for key in candidate_r2_keys(&ctx.env, url, headers)? {
+            for (_, bucket) in ctx.buckets()? {
+                if let Some(obj) = bucket.get(&key).execute().await? {
+                    return Ok(Some(obj));
+                }
+            }
+        }
+        Ok(None)
+    }
+    .await;
+    match result {
+        Ok(obj) => Ok(obj),
+        Err(e) => {
+            tracing::warn!(url = url, error = %e, "R2 read failed, treating as a miss");
+            Ok(None)
+        }
+    }
 }
 
-#[event(fetch)]
-async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
-    Router::new()
-        .get_async("/", get)
-        .post_async("/", post)
-        .run(req, env)
-        .await
+/// Builds a response for `obj`, reusing `bytes` when its body has already been read.
+///
+/// Otherwise the R2 body is handed to the runtime as a stream, so even large objects are
+/// never copied into the isolate; only objects stored without a content type are read to
+/// sniff one.
+async fn response_from_r2(obj: &Object, bytes: Option<Vec<u8>>) -> Result<Option<Response>> {
+    let metadata = obj.custom_metadata()?;
+    let (mut res, content_type) = match (bytes, metadata.get("content_type")) {
+        (Some(bytes), Some(content_type)) => {
+            (Response::from_bytes(bytes)?, Some(content_type.as_str()))
+        }
+        (Some(bytes), None) => {
+            let content_type = sniff::image_content_type(&bytes);
+            (Response::from_bytes(bytes)?, content_type)
+        }
+        (None, content_type) => {
+            let Some(body) = obj.body() else {
+                return Ok(None);
+            };
+            match content_type {
+                Some(content_type) => (
+                    Response::from_body(body.response_body()?)?,
+                    Some(content_type.as_str()),
+                ),
+                None => {
+                    // objects stored before the content type was recorded
+                    let bytes = body.bytes().await?;
+                    let content_type = sniff::image_content_type(&bytes);
+                    (Response::from_bytes(bytes)?, content_type)
+                }
+            }
+        }
+    };
+    if let Some(content_type) = content_type {
+        res.headers_mut().set("Content-Type", content_type)?;
+    }
+    for (header, value) in replayed_headers(&metadata) {
+        res.headers_mut().set(header, value)?;
+    }
+    if !metadata.contains_key("etag") {
+        res.headers_mut().set("ETag", &object_etag(obj)?)?;
+    }
+    if metadata.get("svg_sanitized").map(String::as_str) == Some("true") {
+        svg::apply_headers(res.headers_mut())?;
+    }
+    if let Some(content_length) = metadata.get("content_length") {
+        res.headers_mut().set("Content-Length", content_length)?;
+    }
+    res.headers_mut().set("Accept-Ranges", "bytes")?;
+    let now = now_secs();
+    let stored_at = stored_at(obj)?;
+    res.headers_mut()
+        .set("Age", &freshness::age(stored_at, now).to_string())?;
+    if let Some(expires_at) = expires_at(obj)? {
+        let max_age = freshness::remaining_ttl(expires_at, now);
+        res.headers_mut()
+            .set("Cache-Control", &format!("public, max-age={max_age}"))?;
+    }
+    Ok(Some(res))
+}
+
+/// Reads the body of `obj` and checks it against the SHA-256 recorded by [`put_in_r2`].
+///
+/// Returns `Ok(None)` after deleting the object when it does not match, so that callers
+/// treat it as a miss. Objects without a recorded hash, stored before it was recorded or
+/// streamed in, are trusted.
+async fn verified_body(ctx: &Shared, obj: &Object) -> Result<Option<Vec<u8>>> {
+    let Some(body) = obj.body() else {
+        return Ok(None);
+    };
+    let bytes = body.bytes().await?;
+    let metadata = obj.custom_metadata()?;
+    let Some(expected) = metadata.get("content_sha256") else {
+        return Ok(Some(bytes));
+    };
+    let actual = sha256_hex(&bytes);
+    if &actual != expected {
+        tracing::warn!(
+            key = obj.key(),
+            expected = expected,
+            actual = actual,
+            size = bytes.len(),
+            "object in R2 failed integrity check, deleting it",
+        );
+        ctx.bucket_of(obj)?.delete(obj.key()).await?;
+        return Ok(None);
+    }
+    Ok(Some(bytes))
+}
+
+/// Serves an object stored gzipped, as is to clients accepting gzip and decompressed to
+/// everyone else. Ranges are not supported, since they would apply to the encoded bytes.
+async fn serve_gzipped(
+    obj: &Object,
+    bytes: Option<Vec<u8>>,
+    headers: &Headers,
+) -> Result<Option<Response>> {
+    let bytes = match (bytes, obj.body()) {
+        (Some(bytes), _) => bytes,
+        (None, Some(body)) => body.bytes().await?,
+        (None, None) => return Ok(None),
+    };
+    let accepts_gzip = compress::accepts_gzip(headers.get("Accept-Encoding")?.as_deref());
+    let (bytes, content_encoding) = if accepts_gzip {
+        (bytes, Some("gzip"))
+    } else {
+        let decoded = compress::gunzip(&bytes)
+            .map_err(|e| Error::from(format!("stored object is not valid gzip: {e}")))?;
+        (decoded, None)
+    };
+    let content_length = bytes.len().to_string();
+    let Some(mut res) = response_from_r2(obj, Some(bytes)).await? else {
+        return Ok(None);
+    };
+    res.headers_mut().delete("Accept-Ranges")?;
+    res.headers_mut().set("Content-Length", &content_length)?;
+    res.headers_mut().append("Vary", "Accept-Encoding")?;
+    if let Some(content_encoding) = content_encoding {
+        res.headers_mut()
+            .set("Content-Encoding", content_encoding)?;
+        // the body is already encoded, so the runtime must not compress it again
+        res = res.with_encode_body(EncodeBody::Manual);
+    }
+    Ok(Some(res))
+}
+
+/// The public URL of `obj` to redirect to with `SERVE_MODE=redirect`, or `None` to proxy
+/// it as usual.
+///
+/// Only the hot tier has `R2_PUBLIC_BASE`, and objects stored gzipped or needing the SVG
+/// headers cannot be served by R2's public endpoint as they are.
+fn public_url(
+    ctx: &Shared,
+    obj: &Object,
+    metadata: &HashMap<String, String>,
+) -> Result<Option<String>> {
+    let mode = ctx
+        .env
+        .var("SERVE_MODE")
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| "proxy".to_string());
+    match mode.as_str() {
+        "proxy" => return Ok(None),
+        "redirect" => {}
+        other => return Err(Error::from(format!("invalid SERVE_MODE: {other}"))),
+    }
+    let Ok(base) = ctx.env.var("R2_PUBLIC_BASE") else {
+        return Ok(None);
+    };
+    let proxied = metadata.get("tier").is_some_and(|tier| tier != HOT_TIER)
+        || metadata.contains_key("content_encoding")
+        || metadata.contains_key("svg_sanitized");
+    if proxied {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "{}/{}",
+        base.to_string().trim_end_matches('/'),
+        obj.key()
+    )))
+}
+
+/// Serves `obj`, honoring a `Range` request header with a ranged R2 read.
+async fn serve_cached(ctx: &Shared, obj: &Object, headers: &Headers) -> Result<Option<Response>> {
+    let metadata = obj.custom_metadata()?;
+    if let Some(res) = not_modified(
+        headers,
+        Some(&object_etag(obj)?),
+        metadata.get("last_modified").map(String::as_str),
+    )? {
+        return Ok(Some(res));
+    }
+    if let Some(public_url) = public_url(ctx, obj, &metadata)? {
+        return Ok(Some(Response::redirect_with_status(
+            Url::parse(&public_url)?,
+            302,
+        )?));
+    }
+    let verified = if var_parse::<bool>(&ctx.env, "VERIFY_INTEGRITY")?.unwrap_or(false) {
+        let Some(bytes) = verified_body(ctx, obj).await? else {
+            return Ok(None);
+        };
+        Some(bytes)
+    } else {
+        None
+    };
+    if metadata.get("content_encoding").map(String::as_str) == Some("gzip") {
+        return serve_gzipped(obj, verified, headers).await;
+    }
+    // a range of a representation the client no longer has is answered with all of it
+    let if_range_matches = match headers.get("If-Range")? {
+        Some(if_range) => conditional::if_range_matches(
+            &if_range,
+            Some(&object_etag(obj)?),
+            metadata.get("last_modified").map(String::as_str),
+        ),
+        None => true,
+    };
+    let Some(range) = headers
+        .get("Range")?
+        .and_then(|h| range::ByteRange::parse(&h))
+        .filter(|_| if_range_matches)
+    else {
+        return response_from_r2(obj, verified).await;
+    };
+    let size = obj.size();
+    let Some((start, end)) = range.resolve(size) else {
+        let res = Response::empty()?.with_status(416);
+        res.headers()
+            .set("Content-Range", &format!("bytes */{size}"))?;
+        return Ok(Some(res));
+    };
+    let bucket = ctx.bucket_of(obj)?;
+    let partial = bucket
+        .get(obj.key())
+        .range(R2Range::OffsetWithLength {
+            offset: start,
+            length: end - start + 1,
+        })
+        .execute()
+        .await?;
+    let Some(partial) = partial else {
+        return Ok(None);
+    };
+    let Some(mut res) = response_from_r2(&partial, None).await? else {
+        return Ok(None);
+    };
+    res = res.with_status(206);
+    res.headers_mut()
+        .set("Content-Range", &format!("bytes {start}-{end}/{size}"))?;
+    res.headers_mut()
+        .set("Content-Length", &(end - start + 1).to_string())?;
+    Ok(Some(res))
+}
+
+#[derive(PartialEq)]
+enum CacheStrategy {
+    CacheFirst,
+    OriginFirst,
+}
+
+fn cache_strategy(ctx: &Shared) -> Result<CacheStrategy> {
+    let Ok(strategy) = ctx.env.var("CACHE_STRATEGY") else {
+        return Ok(CacheStrategy::OriginFirst);
+    };
+    match strategy.to_string().as_str() {
+        "cache_first" => Ok(CacheStrategy::CacheFirst),
+        "origin_first" => Ok(CacheStrategy::OriginFirst),
+        other => Err(Error::from(format!("invalid CACHE_STRATEGY: {other}"))),
+    }
+}
+
+fn is_allowed_content_type(ctx: &Shared, content_type: &str) -> bool {
+    let content_type = content_type.to_ascii_lowercase();
+    var_list(&ctx.env, "ALLOWED_CONTENT_TYPES", "image/")
+        .iter()
+        .any(|prefix| content_type.starts_with(&prefix.to_ascii_lowercase()))
+}
+
+/// Per-request knobs taken from the client request rather than the environment.
+#[derive(Default)]
+struct RequestOptions {
+    /// Selects `FALLBACK_URL_<TYPE>` when the origin fails.
+    fallback_type: Option<String>,
+    /// Skips the R2 lookup and overwrites the cached object with a fresh origin copy.
+    refresh: bool,
+    /// Writes to R2 before responding, for callers that report whether the object was
+    /// stored.
+    await_put: bool,
+    /// Serves whatever R2 holds, however stale, and never contacts the origin.
+    only_cached: bool,
+    /// TTL in seconds stored instead of the one derived from the origin's headers.
+    ttl: Option<u64>,
+    /// The client's `X-Cache-Tag`, recorded on the stored object.
+    tag: Option<String>,
+    /// What happened to the request, reported with `DEBUG_HEADERS` and to `ANALYTICS`.
+    diagnostics: RefCell<Diagnostics>,
+    /// The fetch lock held while this request fetches the origin, taken by the background
+    /// put that stores its response so that it is released only after the write.
+    lease: RefCell<Option<Stub>>,
+    /// Events counted while serving the request, recorded together once it is done.
+    stats: RefCell<stats::Counters>,
+}
+
+/// The outcome of a request, for the `X-Cache*` debug headers.
+#[derive(Default)]
+struct Diagnostics {
+    cache: Option<&'static str>,
+    key: Option<String>,
+    origin_status: Option<u16>,
+    origin_latency_ms: Option<u64>,
+    /// Size of the body read from the origin, before any compression for R2.
+    body_bytes: Option<usize>,
+    age: Option<u64>,
+}
+
+impl RequestOptions {
+    fn count(&self, event: stats::Event) {
+        self.stats.borrow_mut().count(event);
+    }
+
+    fn put_options(&self) -> PutOptions {
+        PutOptions {
+            overwrite: self.refresh,
+            ttl: self.ttl,
+            tag: self.tag.clone(),
+            derived: false,
+            key: None,
+        }
+    }
+
+    /// Notes that `obj` was served from R2 as `cache` (`HIT` or `STALE`).
+    fn note_cached(&self, cache: &'static str, obj: &Object) {
+        let mut diagnostics = self.diagnostics.borrow_mut();
+        diagnostics.cache = Some(cache);
+        diagnostics.key = Some(obj.key());
+        diagnostics.age = stored_at(obj)
+            .ok()
+            .map(|stored_at| now_secs().saturating_sub(stored_at));
+    }
+
+    fn note_fallback(&self) {
+        self.diagnostics.borrow_mut().cache = Some("FALLBACK");
+    }
+
+    fn note_origin_status(&self, status: u16) {
+        self.diagnostics.borrow_mut().origin_status = Some(status);
+    }
+
+    fn note_origin_latency(&self, latency_ms: u64) {
+        self.diagnostics.borrow_mut().origin_latency_ms = Some(latency_ms);
+    }
+
+    fn note_body_bytes(&self, body_bytes: usize) {
+        self.diagnostics.borrow_mut().body_bytes = Some(body_bytes);
+    }
+}
+
+/// Adds the `X-Cache*` headers describing how `url_str` was served when `DEBUG_HEADERS`
+/// is enabled.
+fn with_debug_headers(
+    ctx: &Shared,
+    url_str: &str,
+    opts: &RequestOptions,
+    res: Response,
+) -> Result<Response> {
+    if !var_parse::<bool>(&ctx.env, "DEBUG_HEADERS")?.unwrap_or(false) {
+        return Ok(res);
+    }
+    let diagnostics = opts.diagnostics.borrow();
+    // responses passed through from the origin carry immutable headers
+    let headers = res.headers().clone();
+    headers.set("X-Cache", diagnostics.cache.unwrap_or("MISS"))?;
+    let key = match &diagnostics.key {
+        Some(key) => key.clone(),
+        None => get_r2_key(&ctx.env, url_str),
+    };
+    headers.set("X-Cache-Key", &key)?;
+    if let Some(status) = diagnostics.origin_status {
+        headers.set("X-Origin-Status", &status.to_string())?;
+    }
+    if let Some(latency_ms) = diagnostics.origin_latency_ms {
+        headers.set("X-Origin-Latency-Ms", &latency_ms.to_string())?;
+    }
+    if let Some(body_bytes) = diagnostics.body_bytes {
+        headers.set("X-Body-Bytes", &body_bytes.to_string())?;
+    }
+    if let Some(age) = diagnostics.age {
+        headers.set("X-Cache-Age", &age.to_string())?;
+    }
+    Ok(res.with_headers(headers))
+}
+
+/// Picks the fallback image URL for a failed request.
+///
+/// An explicit `type` wins, then the first specific image type in `Accept` (e.g.
+/// `image/svg+xml` selects `FALLBACK_URL_SVG`), then `FALLBACK_URL_DEFAULT`, and finally
+/// `FALLBACK_URL`.
+fn fallback_url(ctx: &Shared, headers: &Headers, opts: &RequestOptions) -> Result<String> {
+    let accept_type = headers.get("Accept")?.and_then(|accept| {
+        accept
+            .split(',')
+            .filter_map(|media| media.split(';').next())
+            .filter_map(|media| media.trim().strip_prefix("image/"))
+            .find(|subtype| *subtype != "*")
+            .map(|subtype| subtype.split('+').next().unwrap_or(subtype).to_string())
+    });
+    let candidates = opts
+        .fallback_type
+        .iter()
+        .chain(accept_type.iter())
+        .map(|kind| {
+            let kind: String = kind
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() {
+                        c.to_ascii_uppercase()
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            format!("FALLBACK_URL_{kind}")
+        })
+        .chain([
+            "FALLBACK_URL_DEFAULT".to_string(),
+            "FALLBACK_URL".to_string(),
+        ]);
+    for name in candidates {
+        if let Ok(url) = ctx.env.var(&name) {
+            return Ok(url.to_string());
+        }
+    }
+    Err(Error::from("FALLBACK_URL is not configured"))
+}
+
+async fn cached_or_fallback(
+    ctx: &Shared,
+    url_str: &str,
+    headers: &Headers,
+    opts: &RequestOptions,
+    origin: Option<&mut Response>,
+) -> Result<Response> {
+    if let Some(obj) = get_from_r2(ctx, url_str, headers).await? {
+        if let Some(cached) = serve_cached(ctx, &obj, headers).await? {
+            tracing::info!(
+                url = url_str,
+                key = obj.key(),
+                "object found in R2, returning cached response",
+            );
+            opts.note_cached(if is_expired(&obj)? { "STALE" } else { "HIT" }, &obj);
+            opts.count(stats::Event::Hit);
+            return Ok(cached);
+        }
+    }
+    let (status, body) = match origin {
+        Some(res) => (
+            Some(res.status_code()),
+            res.text().await.unwrap_or_default(),
+        ),
+        None => (None, String::new()),
+    };
+    tracing::warn!(
+        url = url_str,
+        status = status,
+        body = body,
+        "object not found in R2, returning fallback response",
+    );
+    opts.note_fallback();
+    opts.count(stats::Event::Fallback);
+    let fallback_status = ctx
+        .env
+        .var("FALLBACK_STATUS")
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| "200".to_string());
+    let fallback_status: u16 = match fallback_status.as_str() {
+        // a bodyless 304 or redirect would only confuse the client, so it gets the fallback
+        "origin" if status.is_some_and(errors::is_redirection) => 200,
+        "origin" => return origin_failure_response(ctx, headers, status),
+        status => status
+            .parse()
+            .map_err(|_| Error::from("invalid FALLBACK_STATUS"))?,
+    };
+    Ok(fallback_response(ctx, headers, opts)
+        .await?
+        .with_status(fallback_status))
+}
+
+/// A 1x1 fully transparent PNG, the built-in `FALLBACK_MODE=pixel` image.
+const TRANSPARENT_PIXEL_PNG: &[u8] = &[
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4,
+    0x89, 0x00, 0x00, 0x00, 0x0b, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x63, 0x60, 0x00, 0x02, 0x00,
+    0x00, 0x05, 0x00, 0x01, 0xe9, 0xfa, 0xdc, 0xd8, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44,
+    0xae, 0x42, 0x60, 0x82,
+];
+
+/// Produces the fallback image: proxied from the fallback URL with `FALLBACK_MODE=url` (the
+/// default), or served by the worker itself with `FALLBACK_MODE=pixel`, using
+/// `FALLBACK_IMAGE_BASE64` when set and a transparent pixel otherwise.
+async fn fallback_response(
+    ctx: &Shared,
+    headers: &Headers,
+    opts: &RequestOptions,
+) -> Result<Response> {
+    let mode = ctx
+        .env
+        .var("FALLBACK_MODE")
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| "url".to_string());
+    match mode.as_str() {
+        "url" => {
+            let url = fallback_url(ctx, headers, opts)?;
+            let fetched = async { Fetch::Url(Url::parse(&url)?).send().await };
+            match fetched.await {
+                Ok(res) if (200..300).contains(&res.status_code()) => with_forwarded_headers(res),
+                // a broken fallback host must not turn every origin failure into a 500
+                Ok(res) => {
+                    tracing::error!(
+                        fallback_url = url,
+                        status = res.status_code(),
+                        "fallback also failed, serving built-in pixel",
+                    );
+                    image_response(TRANSPARENT_PIXEL_PNG.to_vec())
+                }
+                Err(e) => {
+                    tracing::error!(
+                        fallback_url = url,
+                        error = %e,
+                        "fallback also failed, serving built-in pixel",
+                    );
+                    image_response(TRANSPARENT_PIXEL_PNG.to_vec())
+                }
+            }
+        }
+        "pixel" => {
+            let image = match ctx.env.var("FALLBACK_IMAGE_BASE64") {
+                Ok(encoded) => base64::engine::general_purpose::STANDARD
+                    .decode(encoded.to_string().trim())
+                    .map_err(|e| Error::from(format!("invalid FALLBACK_IMAGE_BASE64: {e}")))?,
+                Err(_) => TRANSPARENT_PIXEL_PNG.to_vec(),
+            };
+            image_response(image)
+        }
+        _ => Err(Error::from("invalid FALLBACK_MODE")),
+    }
+}
+
+/// Seconds clients are asked to wait before retrying after a transient origin failure.
+const DEFAULT_RETRY_AFTER_SECONDS: u64 = 60;
+
+/// The response without a fallback, for `FALLBACK_STATUS=origin`. Definite origin answers
+/// such as `404` pass through as they are; a failed fetch, `429` or `5xx` is a transient
+/// `502` with `Retry-After` so that clients back off.
+fn origin_failure_response(
+    ctx: &Shared,
+    headers: &Headers,
+    origin_status: Option<u16>,
+) -> Result<Response> {
+    match origin_status {
+        Some(status) if !errors::is_transient_origin_failure(origin_status) => {
+            Ok(Response::empty()?.with_status(status))
+        }
+        _ => {
+            let retry_after =
+                var_parse(&ctx.env, "RETRY_AFTER_SECONDS")?.unwrap_or(DEFAULT_RETRY_AFTER_SECONDS);
+            let res = errors::bad_gateway(headers, "ORIGIN_UNAVAILABLE", "origin unavailable")?;
+            res.headers().set("Retry-After", &retry_after.to_string())?;
+            Ok(res)
+        }
+    }
+}
+
+/// Serves `image` generated by the worker itself, typed by its magic bytes.
+fn image_response(image: Vec<u8>) -> Result<Response> {
+    let content_type = sniff::image_content_type(&image).unwrap_or("application/octet-stream");
+    let mut res = Response::from_bytes(image)?;
+    res.headers_mut().set("Content-Type", content_type)?;
+    Ok(res)
+}
+
+/// Origin of the synthetic URLs that key objects in the edge cache.
+const EDGE_CACHE_ORIGIN: &str = "https://edge-cache.freshrss-image-cache-proxy.internal";
+
+/// The edge cache key for an R2 key, stable across deployments with the same R2 layout.
+fn edge_cache_key(r2_key: &str) -> String {
+    format!("{EDGE_CACHE_ORIGIN}/{r2_key}")
+}
+
+/// Serves `url_str` from the edge cache when it is enabled with `EDGE_CACHE_TTL`, falling
+/// back to [`serve_url`] and keeping successful responses at the edge for that many
+/// seconds. Range requests and refreshes bypass the edge cache.
+async fn serve_tiered(
+    ctx: &Shared,
+    url_str: &str,
+    headers: &Headers,
+    opts: &RequestOptions,
+) -> Result<Response> {
+    let Some(edge_ttl) = var_parse::<u64>(&ctx.env, "EDGE_CACHE_TTL")? else {
+        return serve_url(ctx, url_str, headers, opts).await;
+    };
+    if opts.refresh || headers.has("Range")? {
+        return serve_url(ctx, url_str, headers, opts).await;
+    }
+    // the first candidate is the negotiated variant, so formats never share an entry
+    let key = edge_cache_key(&candidate_r2_keys(&ctx.env, url_str, headers)?[0]);
+    let cache = Cache::default();
+    if let Some(res) = cache.get(&key, false).await? {
+        tracing::info!(url = url_str, key = key, "object found in edge cache");
+        opts.diagnostics.borrow_mut().cache = Some("EDGE");
+        opts.count(stats::Event::Hit);
+        if let Some(not_modified) = not_modified(
+            headers,
+            res.headers().get("ETag")?.as_deref(),
+            res.headers().get("Last-Modified")?.as_deref(),
+        )? {
+            return Ok(not_modified);
+        }
+        return Ok(res);
+    }
+    let mut res = serve_url(ctx, url_str, headers, opts).await?;
+    if res.status_code() == 200 && opts.diagnostics.borrow().cache != Some("FALLBACK") {
+        let copy = res.cloned()?;
+        // responses passed through from the origin carry immutable headers
+        let copy_headers = copy.headers().clone();
+        copy_headers.set("Cache-Control", &format!("public, max-age={edge_ttl}"))?;
+        let copy = copy.with_headers(copy_headers);
+        ctx.data.wait_until(async move {
+            if let Err(e) = Cache::default().put(&key, copy).await {
+                tracing::warn!(key = key, error = %e, "failed to populate edge cache");
+            }
+        });
+    }
+    Ok(res)
+}
+
+/// Default `MEMORY_CACHE_MAX_BYTES`.
+const DEFAULT_MEMORY_CACHE_MAX_BYTES: usize = 1024 * 1024;
+
+/// The in-memory cache budget, or `None` when `MEMORY_CACHE_MAX_ITEMS` is unset.
+fn memory_cache_limits(env: &Env) -> Result<Option<memory::Limits>> {
+    let Some(max_items) = var_parse(env, "MEMORY_CACHE_MAX_ITEMS")? else {
+        return Ok(None);
+    };
+    let max_bytes =
+        var_parse(env, "MEMORY_CACHE_MAX_BYTES")?.unwrap_or(DEFAULT_MEMORY_CACHE_MAX_BYTES);
+    Ok(Some(memory::Limits {
+        max_items,
+        max_bytes,
+    }))
+}
+
+/// Serves small, hot objects from this isolate's memory ahead of the edge cache and R2,
+/// remembering successful responses of up to [`memory::MAX_OBJECT_BYTES`].
+async fn serve_from_memory(
+    ctx: &Shared,
+    url_str: &str,
+    headers: &Headers,
+    opts: &RequestOptions,
+) -> Result<Response> {
+    let Some(limits) = memory_cache_limits(&ctx.env)? else {
+        return serve_tiered(ctx, url_str, headers, opts).await;
+    };
+    if opts.refresh || headers.has("Range")? {
+        return serve_tiered(ctx, url_str, headers, opts).await;
+    }
+    let key = candidate_r2_keys(&ctx.env, url_str, headers)?.swap_remove(0);
+    if let Some((cached_headers, body)) = memory::get(&key, now_secs()) {
+        tracing::info!(url = url_str, key = key, "object found in memory cache");
+        opts.diagnostics.borrow_mut().cache = Some("MEMORY");
+        opts.count(stats::Event::Hit);
+        let res_headers = Headers::new();
+        for (name, value) in &cached_headers {
+            res_headers.append(name, value)?;
+        }
+        if let Some(not_modified) = not_modified(
+            headers,
+            res_headers.get("ETag")?.as_deref(),
+            res_headers.get("Last-Modified")?.as_deref(),
+        )? {
+            return Ok(not_modified);
+        }
+        return Ok(Response::from_bytes(body)?.with_headers(res_headers));
+    }
+    let mut res = serve_tiered(ctx, url_str, headers, opts).await?;
+    // without a Content-Length the body would have to be read to know whether it fits
+    let small = res
+        .headers()
+        .get("Content-Length")?
+        .and_then(|length| length.parse::<usize>().ok())
+        .is_some_and(|length| length <= memory::MAX_OBJECT_BYTES);
+    if res.status_code() == 200 && small && opts.diagnostics.borrow().cache != Some("FALLBACK") {
+        let now = now_secs();
+        let ttl = freshness::origin_ttl(res.headers().get("Cache-Control")?.as_deref(), None, now)
+            .unwrap_or(memory::MAX_ENTRY_AGE_SECS);
+        let mut copy = res.cloned()?;
+        let cached_headers: memory::HeaderList = copy.headers().entries().collect();
+        ctx.data.wait_until(async move {
+            match copy.bytes().await {
+                Ok(body) => memory::insert(key, cached_headers, body, ttl, now, &limits),
+                Err(e) => tracing::warn!(key = key, error = %e, "failed to fill memory cache"),
+            }
+        });
+    }
+    Ok(res)
+}
+
+async fn cache_url(
+    ctx: &Shared,
+    url_str: &str,
+    headers: &Headers,
+    opts: &RequestOptions,
+) -> Result<Response> {
+    if !is_permitted_domain(ctx, url_str) {
+        tracing::warn!(url = url_str, "rejected url from a disallowed domain");
+        return errors::forbidden(headers, "DOMAIN_NOT_ALLOWED", "domain is not allowed");
+    }
+    let res = serve_from_memory(ctx, url_str, headers, opts).await;
+    match &res {
+        Ok(res) => {
+            let diagnostics = opts.diagnostics.borrow();
+            opts.count(stats::Event::Served {
+                miss: diagnostics.cache.is_none(),
+                bytes: res
+                    .headers()
+                    .get("Content-Length")?
+                    .and_then(|length| length.parse().ok()),
+                origin_latency_ms: diagnostics.origin_latency_ms,
+            });
+        }
+        Err(_) => opts.count(stats::Event::Error),
+    }
+    stats::record(ctx, opts.stats.take());
+    write_analytics(ctx, url_str, opts, res.as_ref().ok())?;
+    with_debug_headers(ctx, url_str, opts, res?)
+}
+
+/// Reports the outcome of `url_str` to the `ANALYTICS` dataset; `res` is `None` on error.
+fn write_analytics(
+    ctx: &Shared,
+    url_str: &str,
+    opts: &RequestOptions,
+    res: Option<&Response>,
+) -> Result<()> {
+    let diagnostics = opts.diagnostics.borrow();
+    let outcome = match res {
+        Some(_) => diagnostics.cache.unwrap_or("MISS").to_ascii_lowercase(),
+        None => "error".to_string(),
+    };
+    let host = url_host(url_str);
+    let header = |name| -> Result<Option<String>> {
+        match res {
+            Some(res) => res.headers().get(name),
+            None => Ok(None),
+        }
+    };
+    analytics::write(
+        &ctx.env,
+        analytics::DataPoint {
+            outcome: &outcome,
+            host: &host,
+            content_type: &header("Content-Type")?.unwrap_or_default(),
+            size: header("Content-Length")?
+                .and_then(|len| len.parse().ok())
+                .unwrap_or(0),
+            latency_ms: diagnostics.origin_latency_ms.unwrap_or(0),
+        },
+    );
+    Ok(())
+}
+
+async fn serve_url(
+    ctx: &Shared,
+    url_str: &str,
+    headers: &Headers,
+    opts: &RequestOptions,
+) -> Result<Response> {
+    if opts.only_cached && !opts.refresh {
+        let Some(obj) = get_from_r2(ctx, url_str, headers).await? else {
+            tracing::info!(
+                url = url_str,
+                "only cached copies accepted and none is stored"
+            );
+            return errors::respond(headers, 504, "NOT_CACHED", "not cached");
+        };
+        let Some(res) = serve_cached(ctx, &obj, headers).await? else {
+            return errors::respond(headers, 504, "NOT_CACHED", "not cached");
+        };
+        opts.note_cached(if is_expired(&obj)? { "STALE" } else { "HIT" }, &obj);
+        opts.count(stats::Event::Hit);
+        gc::touch_in_background(ctx, &obj)?;
+        return Ok(res);
+    }
+    let mut stale = None;
+    if opts.refresh {
+        tracing::info!(url = url_str, "refresh requested, skipping R2 lookup");
+    } else if cache_strategy(ctx)? == CacheStrategy::CacheFirst {
+        if let Some(obj) = get_from_r2(ctx, url_str, headers).await? {
+            if is_expired(&obj)? {
+                if is_within_stale_window(ctx, &obj)? {
+                    if let Some(res) = serve_cached(ctx, &obj, headers).await? {
+                        tracing::info!(
+                            url = url_str,
+                            key = obj.key(),
+                            "serving stale object from R2, revalidating in background",
+                        );
+                        opts.note_cached("STALE", &obj);
+                        opts.count(stats::Event::Hit);
+                        gc::touch_in_background(ctx, &obj)?;
+                        revalidate_in_background(ctx, url_str, headers, &obj)?;
+                        return Ok(res);
+                    }
+                }
+                tracing::info!(
+                    url = url_str,
+                    key = obj.key(),
+                    "object in R2 has expired, refetching origin",
+                );
+                stale = Some(obj);
+            } else if let Some(res) = serve_cached(ctx, &obj, headers).await? {
+                tracing::info!(
+                    url = url_str,
+                    key = obj.key(),
+                    "object found in R2, skipping origin fetch",
+                );
+                opts.note_cached("HIT", &obj);
+                opts.count(stats::Event::Hit);
+                gc::touch_in_background(ctx, &obj)?;
+                return Ok(res);
+            }
+        }
+    }
+    // checked before taking the lease, which these early returns would never release
+    if !opts.refresh && is_negatively_cached(ctx, url_str).await {
+        tracing::info!(
+            url = url_str,
+            "origin recently returned not found, skipping origin fetch",
+        );
+        return cached_or_fallback(ctx, url_str, headers, opts, None).await;
+    }
+    if !ratelimit::try_acquire(&ctx.env, &url_host(url_str)).await? {
+        tracing::warn!(
+            url = url_str,
+            "origin host is over PER_HOST_RPS, skipping fetch"
+        );
+        let res = cached_or_fallback(ctx, url_str, headers, opts, None).await?;
+        // responses passed through from the origin carry immutable headers
+        let res_headers = res.headers().clone();
+        res_headers.set("X-RateLimited", "true")?;
+        return Ok(res.with_headers(res_headers));
+    }
+    let lease = coalesce::acquire(&ctx.env, &get_r2_key(&ctx.env, url_str)).await?;
+    if let coalesce::Lease::Follower = lease {
+        if let Some(obj) = get_from_r2(ctx, url_str, headers).await? {
+            if !is_expired(&obj)? {
+                if let Some(res) = serve_cached(ctx, &obj, headers).await? {
+                    tracing::info!(
+                        url = url_str,
+                        key = obj.key(),
+                        "object stored by concurrent request, skipping origin fetch",
+                    );
+                    opts.note_cached("HIT", &obj);
+                    opts.count(stats::Event::Hit);
+                    return Ok(res);
+                }
+            }
+        }
+    }
+    opts.count(stats::Event::OriginFetch);
+    if let coalesce::Lease::Leader(stub) = lease {
+        opts.lease.replace(Some(stub));
+    }
+    let res = fetch_origin(ctx, url_str, headers, opts, stale.as_ref()).await;
+    // still here when nothing was written in the background
+    if let Some(stub) = opts.lease.take() {
+        release_lease(url_str, &stub).await;
+    }
+    res
+}
+
+/// With `SNIFF_CONTENT` enabled, checks that `body` starts with a known image signature
+/// rather than trusting the origin's `Content-Type`.
+fn sniffs_as_image(ctx: &Shared, body: &[u8]) -> Result<bool> {
+    if !var_parse::<bool>(&ctx.env, "SNIFF_CONTENT")?.unwrap_or(false) {
+        return Ok(true);
+    }
+    Ok(sniff::image_content_type(body).is_some())
+}
+
+/// Whether `SANITIZE_SVG` is on and `body` is an SVG, by its `content_type` or its
+/// leading bytes.
+fn sanitizes_svg(ctx: &Shared, content_type: Option<&str>, body: &[u8]) -> Result<bool> {
+    if !var_parse::<bool>(&ctx.env, "SANITIZE_SVG")?.unwrap_or(false) {
+        return Ok(false);
+    }
+    let declared = content_type.is_some_and(|content_type| {
+        content_type
+            .split(';')
+            .next()
+            .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("image/svg+xml"))
+    });
+    Ok(declared || sniff::image_content_type(body) == Some("image/svg+xml"))
+}
+
+/// Bodies shorter than this are not images but truncated or empty responses.
+const DEFAULT_MIN_OBJECT_BYTES: usize = 8;
+
+/// Whether `body` is shorter than `MIN_OBJECT_BYTES` and must not be cached, so that a
+/// `200` with an empty body does not leave a blank image in R2.
+fn is_too_small(ctx: &Shared, body: &[u8]) -> Result<bool> {
+    let min_bytes = var_parse(&ctx.env, "MIN_OBJECT_BYTES")?.unwrap_or(DEFAULT_MIN_OBJECT_BYTES);
+    Ok(body.len() < min_bytes)
+}
+
+/// How many times its encoded size a compressed origin body may inflate to by default.
+const DEFAULT_MAX_DECOMP_RATIO: f64 = 100.0;
+
+/// `MAX_DECOMP_RATIO`, the inflation allowed for `Content-Encoding` compressed bodies.
+fn max_decomp_ratio(ctx: &Shared) -> Result<f64> {
+    Ok(var_parse(&ctx.env, "MAX_DECOMP_RATIO")?.unwrap_or(DEFAULT_MAX_DECOMP_RATIO))
+}
+
+/// Why [`read_body_limited`] gave up on a body.
+#[derive(Debug, PartialEq)]
+enum Oversized {
+    /// The body is larger than allowed as sent.
+    TooLarge,
+    /// A compressed body inflated past the limit or the decompression ratio, which
+    /// suggests a decompression bomb.
+    Inflated,
+}
+
+/// Most an origin body is buffered to when `MAX_OBJECT_BYTES` is unset, well within the
+/// isolate's 128 MB of memory. Streamed puts never hold the body and are not bound by it.
+const DEFAULT_MAX_OBJECT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Whether `observed` bytes of a body read so far are already too many: more than
+/// `max_bytes`, or for an `encoded` body more than `max_inflated`.
+fn oversized(
+    observed: u64,
+    encoded: bool,
+    max_bytes: u64,
+    max_inflated: Option<u64>,
+) -> Option<Oversized> {
+    if max_inflated.is_some_and(|max_inflated| observed > max_inflated) {
+        return Some(Oversized::Inflated);
+    }
+    (observed > max_bytes).then_some(if encoded {
+        Oversized::Inflated
+    } else {
+        Oversized::TooLarge
+    })
+}
+
+/// Origin bodies this long or longer are streamed into R2 by default.
+const DEFAULT_STREAM_PUT_BYTES: u64 = 4 * 1024 * 1024;
+
+/// The length of the origin body of `res` if it should be streamed into R2 rather than
+/// buffered.
+///
+/// Streaming needs the exact length up front, so compressed bodies, whose
+/// `Content-Length` the runtime's decoding invalidates, are buffered. So is anything a
+/// buffered put would derive from the body: sniffing, SVG sanitizing, placeholders,
+/// transcoding and gzipping.
+fn streamed_put_length(
+    ctx: &Shared,
+    headers: &Headers,
+    res: &Response,
+    content_type: &str,
+    max_bytes: Option<u64>,
+) -> Result<Option<u64>> {
+    let threshold = var_parse(&ctx.env, "STREAM_PUT_BYTES")?.unwrap_or(DEFAULT_STREAM_PUT_BYTES);
+    let Some(length) = stream_length(
+        res.headers().get("Content-Length")?.as_deref(),
+        res.headers().get("Content-Encoding")?.as_deref(),
+        threshold,
+        max_bytes,
+    ) else {
+        return Ok(None);
+    };
+    for flag in ["SNIFF_CONTENT", "SANITIZE_SVG", "GENERATE_LQIP"] {
+        if var_parse::<bool>(&ctx.env, flag)?.unwrap_or(false) {
+            return Ok(None);
+        }
+    }
+    if compress::is_compressible(content_type) || transcode_target(&ctx.env, headers)?.is_some() {
+        return Ok(None);
+    }
+    Ok(Some(length))
+}
+
+/// The `content_length` of an identity-encoded body that is at least `threshold` and at
+/// most `max_bytes` long.
+fn stream_length(
+    content_length: Option<&str>,
+    content_encoding: Option<&str>,
+    threshold: u64,
+    max_bytes: Option<u64>,
+) -> Option<u64> {
+    let encoded = content_encoding.is_some_and(|encoding| {
+        let encoding = encoding.trim();
+        !encoding.is_empty() && !encoding.eq_ignore_ascii_case("identity")
+    });
+    let length = content_length?.trim().parse().ok()?;
+    (!encoded && length >= threshold && max_bytes.is_none_or(|max| length <= max)).then_some(length)
+}
+
+/// Buffers the body of `res`, giving up as soon as it exceeds `max_bytes`, or
+/// [`DEFAULT_MAX_OBJECT_BYTES`] when that is unset.
+///
+/// The runtime decodes `Content-Encoding` as the body is read, so for compressed bodies
+/// `Content-Length` is the encoded size, and the decoded body is also held to
+/// `max_ratio` times that. Without a `Content-Length` only `max_bytes` bounds it.
+async fn read_body_limited(
+    url_str: &str,
+    res: &mut Response,
+    max_bytes: Option<u64>,
+    max_ratio: f64,
+) -> Result<std::result::Result<Vec<u8>, Oversized>> {
+    let max_bytes = max_bytes.unwrap_or(DEFAULT_MAX_OBJECT_BYTES);
+    let content_length = res
+        .headers()
+        .get("Content-Length")?
+        .and_then(|len| len.parse::<u64>().ok());
+    let encoded = res
+        .headers()
+        .get("Content-Encoding")?
+        .is_some_and(|encoding| {
+            let encoding = encoding.trim();
+            !encoding.is_empty() && !encoding.eq_ignore_ascii_case("identity")
+        });
+    if let Some(content_length) = content_length {
+        if content_length > max_bytes {
+            tracing::warn!(
+                url = url_str,
+                content_length = content_length,
+                "origin Content-Length exceeds MAX_OBJECT_BYTES, skipping put",
+            );
+            return Ok(Err(Oversized::TooLarge));
+        }
+    }
+    let max_inflated = match (encoded, content_length) {
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss,
+            clippy::cast_precision_loss
+        )]
+        (true, Some(content_length)) => Some((content_length as f64 * max_ratio) as u64),
+        _ => None,
+    };
+    let mut stream = res.stream()?;
+    let mut body = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk?);
+        let observed = body.len() as u64;
+        let Some(oversized) = oversized(observed, encoded, max_bytes, max_inflated) else {
+            continue;
+        };
+        if max_inflated.is_some_and(|max_inflated| observed > max_inflated) {
+            tracing::warn!(
+                url = url_str,
+                content_length = content_length,
+                observed = observed,
+                "compressed origin body exceeds MAX_DECOMP_RATIO, aborting",
+            );
+        } else {
+            tracing::warn!(
+                url = url_str,
+                observed = observed,
+                encoded = encoded,
+                "origin body exceeds MAX_OBJECT_BYTES, skipping put",
+            );
+        }
+        return Ok(Err(oversized));
+    }
+    Ok(Ok(body))
+}
+
+/// Delay before the first retry of a failed origin fetch, doubled on every attempt.
+const RETRY_BASE_DELAY_MS: u64 = 200;
+
+const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/142.0.0.0 Safari/537.36";
+
+/// Reads `PROXY_EXTRA_HEADERS`, a JSON object of header names to values.
+fn proxy_extra_headers(ctx: &Shared) -> Result<HashMap<String, String>> {
+    let Ok(extra) = ctx.env.var("PROXY_EXTRA_HEADERS") else {
+        return Ok(HashMap::new());
+    };
+    serde_json::from_str(&extra.to_string())
+        .map_err(|e| Error::from(format!("invalid PROXY_EXTRA_HEADERS: {e}")))
+}
+
+/// The `Referer` to send to `url_str` under `REFERER_STRATEGY`: the URL's own origin by
+/// default (`origin`), nothing (`none`), or `FIXED_REFERER` (`fixed`).
+fn referer(ctx: &Shared, url_str: &str) -> Result<Option<String>> {
+    let strategy = ctx
+        .env
+        .var("REFERER_STRATEGY")
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| "origin".to_string());
+    referer_for(&strategy, url_str, || {
+        Ok(ctx.env.var("FIXED_REFERER")?.to_string())
+    })
+}
+
+/// The `Referer` `strategy` builds for `url_str`, reading the `fixed` one only when it is
+/// used.
+fn referer_for(
+    strategy: &str,
+    url_str: &str,
+    fixed: impl FnOnce() -> Result<String>,
+) -> Result<Option<String>> {
+    match strategy {
+        "origin" => Ok(Url::parse(url_str)
+            .ok()
+            .map(|url| format!("{}/", url.origin().ascii_serialization()))),
+        "none" => Ok(None),
+        "fixed" => fixed().map(Some),
+        _ => Err(Error::from("invalid REFERER_STRATEGY")),
+    }
+}
+
+/// A stored copy's validators, sent to the origin to revalidate it.
+#[derive(Default)]
+struct Validators {
+    last_modified: Option<String>,
+    etag: Option<String>,
+}
+
+impl Validators {
+    fn of(obj: &Object) -> Result<Self> {
+        let mut metadata = obj.custom_metadata()?;
+        Ok(Self {
+            last_modified: metadata.remove("last_modified"),
+            etag: metadata.remove("etag"),
+        })
+    }
+}
+
+/// Builds the origin request, revalidating with `If-None-Match` and `If-Modified-Since`
+/// when a stale copy's `ETag` and `Last-Modified` are known. The client's own validators
+/// are never forwarded.
+///
+/// The User-Agent is `PROXY_USER_AGENT`, else the client's, else a desktop Chrome UA.
+/// `PROXY_EXTRA_HEADERS` is applied on top and may override the User-Agent, the
+/// forwarded `Accept` or the [`referer`], but never `Range` or the revalidation validators.
+fn origin_request(
+    ctx: &Shared,
+    url_str: &str,
+    method: worker::Method,
+    headers: &Headers,
+    validators: &Validators,
+) -> Result<Request> {
+    let h = Headers::new();
+    let user_agent = match ctx.env.var("PROXY_USER_AGENT") {
+        Ok(user_agent) => Some(user_agent.to_string()),
+        Err(_) => headers.get("User-Agent")?,
+    };
+    h.set(
+        "User-Agent",
+        &user_agent.unwrap_or(DEFAULT_USER_AGENT.into()),
+    )?;
+    if let Some(accept) = headers.get("Accept")? {
+        h.set("Accept", &accept)?;
+    }
+    // `Accept-Encoding` is left to the runtime, which negotiates compression with the
+    // origin itself and decodes the body before it is read, so storing the origin's
+    // compressed bytes is not possible
+    if let Some(referer) = referer(ctx, url_str)? {
+        h.set("Referer", &referer)?;
+    }
+    for (name, value) in proxy_extra_headers(ctx)? {
+        h.set(&name, &value)?;
+    }
+    if let Some(range) = headers.get("Range")? {
+        h.set("Range", &range)?;
+    }
+    if let Some(etag) = &validators.etag {
+        h.set("If-None-Match", etag)?;
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        h.set("If-Modified-Since", last_modified)?;
+    }
+    Request::new_with_init(
+        url_str,
+        &worker::RequestInit {
+            headers: h,
+            method,
+            // redirects are followed by `send_following_redirects` so every hop is checked
+            redirect: worker::RequestRedirect::Manual,
+            ..Default::default()
+        },
+    )
+}
+
+/// Sends `req`, aborting it once `timeout_ms` elapses.
+async fn send_with_timeout(
+    url_str: &str,
+    req: Request,
+    timeout_ms: Option<u64>,
+) -> Result<Response> {
+    let fetch = Fetch::Request(req);
+    let Some(timeout_ms) = timeout_ms else {
+        return fetch.send().await;
+    };
+    let controller = AbortController::default();
+    let signal = controller.signal();
+    let timeout = Delay::from(Duration::from_millis(timeout_ms));
+    let send = Box::pin(fetch.send_with_signal(&signal));
+    let outcome = future::select(send, timeout).await;
+    match outcome {
+        Either::Left((res, _)) => res,
+        Either::Right(_) => {
+            controller.abort();
+            tracing::warn!(
+                url = url_str,
+                timeout_ms = timeout_ms,
+                "origin fetch timed out",
+            );
+            Err(Error::from("origin fetch timed out"))
+        }
+    }
+}
+
+/// Redirect hops followed before the origin fetch is given up, unless `MAX_REDIRECTS` says
+/// otherwise.
+const DEFAULT_MAX_REDIRECTS: usize = 5;
+
+/// Fetches `url_str`, following redirects manually so that each target passes the same
+/// host checks as the requested URL before the worker connects to it. The response is
+/// still cached under `url_str`, not the final redirected URL.
+async fn send_following_redirects(
+    ctx: &Shared,
+    url_str: &str,
+    method: worker::Method,
+    headers: &Headers,
+    validators: &Validators,
+    timeout_ms: Option<u64>,
+) -> Result<Response> {
+    let started = Date::now().as_millis();
+    let max_redirects = var_parse(&ctx.env, "MAX_REDIRECTS")?.unwrap_or(DEFAULT_MAX_REDIRECTS);
+    let mut current = origin_url(&ctx.env, url_str);
+    let mut chain = vec![current.clone()];
+    for _ in 0..=max_redirects {
+        let remaining_ms =
+            timeout_ms.map(|timeout| timeout.saturating_sub(Date::now().as_millis() - started));
+        let req = origin_request(ctx, &current, method.clone(), headers, validators)?;
+        let res = send_with_timeout(url_str, req, remaining_ms).await?;
+        if !matches!(res.status_code(), 301 | 302 | 303 | 307 | 308) {
+            if chain.len() > 1 {
+                tracing::debug!(url = url_str, chain = ?chain, "followed origin redirects");
+            }
+            return Ok(res);
+        }
+        let Some(location) = res.headers().get("Location")? else {
+            return Ok(res);
+        };
+        let next = Url::parse(&current)?.join(&location)?;
+        if let Some(reason) = blocked_reason(ctx, next.as_str()) {
+            tracing::warn!(
+                url = url_str,
+                location = next.as_str(),
+                reason = reason,
+                "rejected origin redirect",
+            );
+            return Err(Error::from(format!("redirect rejected: {reason}")));
+        }
+        if chain.iter().any(|visited| visited == next.as_str()) {
+            tracing::warn!(
+                url = url_str,
+                location = next.as_str(),
+                chain = ?chain,
+                "origin redirect loop",
+            );
+            return Err(Error::from("redirect loop at origin"));
+        }
+        tracing::info!(
+            url = url_str,
+            from = current,
+            to = next.as_str(),
+            "following origin redirect",
+        );
+        current = next.to_string();
+        chain.push(current.clone());
+    }
+    tracing::warn!(url = url_str, chain = ?chain, "too many origin redirects");
+    Err(Error::from("too many redirects from origin"))
+}
+
+/// Fetches the origin, retrying network errors and 502/503/504 responses up to
+/// `MAX_RETRIES` times with exponential backoff. No retry is started that would end past
+/// the `FETCH_TIMEOUT_MS` budget.
+///
+/// At most `MAX_CONCURRENT_FETCHES` of these run at once per isolate; the rest queue.
+async fn fetch_with_retry(
+    ctx: &Shared,
+    url_str: &str,
+    headers: &Headers,
+    validators: &Validators,
+) -> Result<Response> {
+    let max_fetches = var_parse(&ctx.env, "MAX_CONCURRENT_FETCHES")?.unwrap_or(usize::MAX);
+    let _permit = limiter::acquire(max_fetches).await;
+    let max_retries: u32 = var_parse(&ctx.env, "MAX_RETRIES")?.unwrap_or(0);
+    let budget_ms: Option<u64> = var_parse(&ctx.env, "FETCH_TIMEOUT_MS")?;
+    let started = Date::now().as_millis();
+    let mut attempt = 0;
+    loop {
+        let remaining_ms =
+            budget_ms.map(|budget| budget.saturating_sub(Date::now().as_millis() - started));
+        let result = send_following_redirects(
+            ctx,
+            url_str,
+            worker::Method::Get,
+            headers,
+            validators,
+            remaining_ms,
+        )
+        .await;
+        let retryable = match &result {
+            Ok(res) => matches!(res.status_code(), 502..=504),
+            Err(_) => true,
+        };
+        if !retryable || attempt >= max_retries {
+            return result;
+        }
+        let delay_ms = RETRY_BASE_DELAY_MS << attempt;
+        let elapsed_ms = Date::now().as_millis() - started;
+        if budget_ms.is_some_and(|budget| elapsed_ms + delay_ms >= budget) {
+            return result;
+        }
+        attempt += 1;
+        tracing::warn!(
+            url = url_str,
+            attempt = attempt,
+            delay_ms = delay_ms,
+            "transient origin failure, retrying",
+        );
+        Delay::from(Duration::from_millis(delay_ms)).await;
+    }
+}
+
+/// Refreshes the `stale` R2 copy of `url_str` after the response has been sent.
+fn revalidate_in_background(
+    ctx: &Shared,
+    url_str: &str,
+    headers: &Headers,
+    stale: &Object,
+) -> Result<()> {
+    // only the client's identity and Accept are forwarded, never its Range or validators
+    let origin_headers = Headers::new();
+    for name in ["User-Agent", "Accept"] {
+        if let Some(value) = headers.get(name)? {
+            origin_headers.set(name, &value)?;
+        }
+    }
+    let ctx = ctx.clone();
+    let url = url_str.to_string();
+    let key = stale.key();
+    let validators = Validators::of(stale)?;
+    ctx.data.clone().wait_until(async move {
+        // shares the lease of foreground fetches, so a burst of stale hits sends one
+        // conditional request and expired-object fetches wait for its result
+        let lease = match coalesce::try_acquire(&ctx.env, &get_r2_key(&ctx.env, &url)).await {
+            Ok(coalesce::Lease::Follower) => {
+                tracing::info!(url = url, "revalidation already in flight, skipping");
+                return;
+            }
+            Ok(lease) => lease,
+            Err(e) => {
+                tracing::warn!(url = url, error = %e, "fetch lock unavailable, revalidating anyway");
+                coalesce::Lease::Unavailable
+            }
+        };
+        if let Err(e) =
+            revalidate(&ctx, &url, &origin_headers, &key, &validators).await
+        {
+            tracing::warn!(url = url, error = %e, "background revalidation failed");
+        }
+        if let coalesce::Lease::Leader(stub) = lease {
+            release_lease(&url, &stub).await;
+        }
+    });
+    Ok(())
+}
+
+async fn revalidate(
+    ctx: &Shared,
+    url_str: &str,
+    headers: &Headers,
+    key: &str,
+    validators: &Validators,
+) -> Result<()> {
+    let mut res = fetch_with_retry(ctx, url_str, headers, validators).await?;
+    if res.status_code() == 304 {
+        return refresh_ttl(ctx, key, res.headers()).await;
+    }
+    if res.status_code() != 200 {
+        return Err(Error::from(format!(
+            "origin responded with status {}",
+            res.status_code()
+        )));
+    }
+    let content_type = res.headers().get("Content-Type")?.unwrap_or_default();
+    if !is_allowed_content_type(ctx, &content_type) {
+        return Err(Error::from(format!(
+            "disallowed content type {content_type}"
+        )));
+    }
+    let max_bytes = var_parse(&ctx.env, "MAX_OBJECT_BYTES")?;
+    let max_ratio = max_decomp_ratio(ctx)?;
+    if let Ok(body) = read_body_limited(url_str, &mut res, max_bytes, max_ratio).await? {
+        if is_too_small(ctx, &body)? {
+            return Err(Error::from("origin body is shorter than MIN_OBJECT_BYTES"));
+        }
+        if !sniffs_as_image(ctx, &body)? {
+            return Err(Error::from("origin body is not an image"));
+        }
+        put_in_r2(
+            ctx,
+            url_str,
+            negotiated_format(headers)?,
+            res.headers(),
+            body,
+            // a stored TTL override and tag are carried over by `put_in_r2`
+            &PutOptions::default(),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Re-stores the object at `key` with an expiry computed from the origin's `304` headers,
+/// reusing the body already in R2.
+async fn refresh_ttl(ctx: &Shared, key: &str, headers: &Headers) -> Result<()> {
+    if is_read_only(&ctx.env) {
+        return Ok(());
+    }
+    let Some((bucket, obj)) = ctx.get_any_tier(key).await? else {
+        return Ok(());
+    };
+    let Some(body) = obj.body() else {
+        return Ok(());
+    };
+    let mut metadata = obj.custom_metadata()?;
+    let now = now_secs();
+    // the origin just vouched for the body, so it is as fresh as a new fetch
+    metadata.insert("stored_at".to_string(), now.to_string());
+    let ttl_override = metadata
+        .get("ttl_override")
+        .and_then(|ttl| ttl.parse().ok());
+    let ttl = match ttl_override {
+        Some(ttl) => Some(ttl),
+        None => ttl_for(ctx, headers, now)?
+            .map(|ttl| jittered_ttl(ctx, ttl))
+            .transpose()?,
+    };
+    match ttl {
+        Some(ttl) => metadata.insert("expires_at".to_string(), (now + ttl).to_string()),
+        None => metadata.remove("expires_at"),
+    };
+    // a newer copy stored meanwhile is fresher than this one
+    if !r2::put_if_match(
+        bucket,
+        key,
+        r2::restream(body, obj.size())?,
+        obj.http_metadata(),
+        metadata,
+        &obj.etag(),
+    )
+    .await?
+    {
+        tracing::info!(key = key, "object changed in R2, skipping TTL refresh");
+        return Ok(());
+    }
+    tracing::info!(key = key, "origin not modified, refreshed TTL of R2 object");
+    Ok(())
+}
+
+/// Reads `MIRROR_HOSTS`, a JSON object of host patterns to lists of alternate hosts.
+fn mirror_hosts(ctx: &Shared) -> Result<BTreeMap<String, Vec<String>>> {
+    let Ok(mirrors) = ctx.env.var("MIRROR_HOSTS") else {
+        return Ok(BTreeMap::new());
+    };
+    serde_json::from_str(&mirrors.to_string())
+        .map_err(|e| Error::from(format!("invalid MIRROR_HOSTS: {e}")))
+}
+
+/// Tries the `MIRROR_HOSTS` alternates of `url_str` in order after the primary origin
+/// failed, returning the first successful response.
+async fn fetch_from_mirrors(
+    ctx: &Shared,
+    url_str: &str,
+    headers: &Headers,
+) -> Result<Option<Response>> {
+    let Ok(url) = Url::parse(url_str) else {
+        return Ok(None);
+    };
+    for mirror in mirrors::mirror_urls(&url, &mirror_hosts(ctx)?) {
+        if let Some(reason) = blocked_reason(ctx, &mirror) {
+            tracing::warn!(
+                url = url_str,
+                mirror = mirror,
+                reason = reason,
+                "skipping mirror"
+            );
+            continue;
+        }
+        // the origin's validators mean nothing to a mirror, so no If-Modified-Since
+        match fetch_with_retry(ctx, &mirror, headers, &Validators::default()).await {
+            Ok(res) if (200..300).contains(&res.status_code()) => {
+                tracing::info!(
+                    url = url_str,
+                    mirror = mirror,
+                    "origin failed, using mirror"
+                );
+                return Ok(Some(res));
+            }
+            Ok(res) => tracing::warn!(
+                url = url_str,
+                mirror = mirror,
+                status = res.status_code(),
+                "mirror returned an error",
+            ),
+            Err(e) => {
+                tracing::warn!(url = url_str, mirror = mirror, error = %e, "mirror fetch failed")
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Fetches `url_str` from the origin, revalidating the `stale` R2 copy when there is one.
+async fn fetch_origin(
+    ctx: &Shared,
+    url_str: &str,
+    headers: &Headers,
+    opts: &RequestOptions,
+    stale: Option<&Object>,
+) -> Result<Response> {
+    let validators = match stale {
+        Some(stale) => Validators::of(stale)?,
+        None => Validators::default(),
+    };
+    let started = Date::now().as_millis();
+    let fetched = match fetch_with_retry(ctx, url_str, headers, &validators).await {
+        Ok(res) if res.status_code() < 400 => Ok(res),
+        primary => match fetch_from_mirrors(ctx, url_str, headers).await? {
+            Some(res) => Ok(res),
+            None => primary,
+        },
+    };
+    opts.note_origin_latency(Date::now().as_millis() - started);
+    let mut res = match fetched {
+        Ok(res) => res,
+        Err(e) => {
+            tracing::warn!(url = url_str, error = %e, "origin fetch failed");
+            return cached_or_fallback(ctx, url_str, headers, opts, None).await;
+        }
+    };
+    opts.note_origin_status(res.status_code());
+    // a 206 nobody asked for is stored only when it holds the whole object
+    let complete_length = match res.status_code() {
+        206 if !headers.has("Range")? => res
+            .headers()
+            .get("Content-Range")?
+            .as_deref()
+            .and_then(range::complete_length),
+        _ => None,
+    };
+    match res.status_code() {
+        206 if complete_length.is_none() => {
+            tracing::info!(
+                url = url_str,
+                "origin returned a partial response, skipping put"
+            );
+            with_forwarded_headers(res)
+        }
+        200..300 => {
+            let content_type = res.headers().get("Content-Type")?.unwrap_or_default();
+            if !is_allowed_content_type(ctx, &content_type) {
+                tracing::warn!(
+                    url = url_str,
+                    content_type = content_type,
+                    "origin returned a disallowed content type, skipping put",
+                );
+                return cached_or_fallback(ctx, url_str, headers, opts, Some(&mut res)).await;
+            }
+            let max_bytes = var_parse(&ctx.env, "MAX_OBJECT_BYTES")?;
+            // awaiting the put would leave the runtime buffering the client's half of the tee
+            let streamed = match res.status_code() {
+                200 if !opts.await_put => {
+                    streamed_put_length(ctx, headers, &res, &content_type, max_bytes)?
+                }
+                _ => None,
+            };
+            if let Some(length) = streamed {
+                let body = res.cloned()?.stream()?;
+                opts.note_body_bytes(usize::try_from(length).unwrap_or(usize::MAX));
+                put_stream_in_r2_in_background(
+                    ctx,
+                    url_str,
+                    negotiated_format(headers)?,
+                    res.headers(),
+                    body,
+                    length,
+                    opts,
+                );
+                if let Some(not_modified) = not_modified(
+                    headers,
+                    res.headers().get("ETag")?.as_deref(),
+                    res.headers().get("Last-Modified")?.as_deref(),
+                )? {
+                    return Ok(not_modified);
+                }
+                let mut res = with_forwarded_headers(res)?;
+                res.headers_mut().set("Age", "0")?;
+                return Ok(res);
+            }
+            let max_ratio = max_decomp_ratio(ctx)?;
+            let mut tee = res.cloned()?;
+            let body = match read_body_limited(url_str, &mut tee, max_bytes, max_ratio).await? {
+                Ok(body) => body,
+                Err(Oversized::TooLarge) => return with_forwarded_headers(res),
+                // neither stored nor passed on, and not read again for the log either
+                Err(Oversized::Inflated) => {
+                    return cached_or_fallback(ctx, url_str, headers, opts, None).await
+                }
+            };
+            opts.note_body_bytes(body.len());
+            if complete_length.is_some_and(|length| length != body.len() as u64) {
+                tracing::warn!(
+                    url = url_str,
+                    length = body.len(),
+                    "partial response does not match its Content-Range, skipping put",
+                );
+                return with_forwarded_headers(res);
+            }
+            if is_too_small(ctx, &body)? {
+                tracing::warn!(
+                    url = url_str,
+                    length = body.len(),
+                    "origin body is shorter than MIN_OBJECT_BYTES, skipping put",
+                );
+                return cached_or_fallback(ctx, url_str, headers, opts, Some(&mut res)).await;
+            }
+            if !sniffs_as_image(ctx, &body)? {
+                tracing::warn!(
+                    url = url_str,
+                    content_type = content_type,
+                    "origin body does not look like an image, skipping put",
+                );
+                return cached_or_fallback(ctx, url_str, headers, opts, Some(&mut res)).await;
+            }
+            // `put_in_r2` stores the same sanitized copy
+            let sanitized_svg =
+                sanitizes_svg(ctx, Some(&content_type), &body)?.then(|| svg::sanitize(&body));
+            let format = negotiated_format(headers)?;
+            if let Some(target) = transcode_target(&ctx.env, headers)? {
+                transcode_in_background(
+                    ctx,
+                    url_str,
+                    target,
+                    res.headers(),
+                    body.clone(),
+                    opts.put_options(),
+                )?;
+            }
+            if opts.await_put {
+                put_in_r2(
+                    ctx,
+                    url_str,
+                    format,
+                    res.headers(),
+                    body,
+                    &opts.put_options(),
+                )
+                .await?;
+            } else {
+                put_in_r2_in_background(ctx, url_str, format, res.headers(), body, opts);
+            }
+            if let Some(not_modified) = not_modified(
+                headers,
+                res.headers().get("ETag")?.as_deref(),
+                res.headers().get("Last-Modified")?.as_deref(),
+            )? {
+                return Ok(not_modified);
+            }
+            let mut res = with_forwarded_headers(res)?;
+            if let Some(sanitized_svg) = sanitized_svg {
+                let headers = res.headers().clone();
+                headers.delete("Content-Length")?;
+                svg::apply_headers(&headers)?;
+                res = Response::from_bytes(sanitized_svg)?.with_headers(headers);
+            }
+            res.headers_mut().set("Age", "0")?;
+            Ok(res)
+        }
+        300..400 => match redirection_outcome(res.status_code(), stale.is_some()) {
+            Redirection::Revalidated => {
+                let stale = stale.expect("revalidated only with a stale copy");
+                tracing::info!(
+                    url = url_str,
+                    "origin returned not modified, serving R2 copy"
+                );
+                if let Err(e) = refresh_ttl(ctx, &stale.key(), res.headers()).await {
+                    tracing::warn!(url = url_str, error = %e, "failed to refresh TTL in R2");
+                }
+                match serve_cached(ctx, stale, headers).await? {
+                    Some(cached) => {
+                        opts.note_cached("HIT", stale);
+                        opts.count(stats::Event::Hit);
+                        Ok(cached)
+                    }
+                    None => cached_or_fallback(ctx, url_str, headers, opts, Some(&mut res)).await,
+                }
+            }
+            Redirection::Fallback => {
+                // a 304 to an unconditional request, or a redirect `send_following_redirects`
+                // gave up on, e.g. without a Location
+                tracing::warn!(
+                    url = url_str,
+                    status = res.status_code(),
+                    "origin returned a 3xx without a body to serve",
+                );
+                cached_or_fallback(ctx, url_str, headers, opts, Some(&mut res)).await
+            }
+        },
+        404 | 410 => {
+            cache_negative_in_background(ctx, url_str, res.status_code())?;
+            cached_or_fallback(ctx, url_str, headers, opts, Some(&mut res)).await
+        }
+        400.. => cached_or_fallback(ctx, url_str, headers, opts, Some(&mut res)).await,
+        _ => Err(Error::from("unexpected status code from origin")),
+    }
+}
+
+/// How to answer a `3xx` from the origin, which never has a body to store.
+#[derive(Debug, PartialEq)]
+enum Redirection {
+    /// Serve the stale copy the revalidation confirmed.
+    Revalidated,
+    /// Serve whatever [`cached_or_fallback`] finds.
+    Fallback,
+}
+
+/// Only a `304` to the revalidation of a `stale` copy confirms one; any other `3xx`, or a
+/// `304` nothing was revalidated for, has nothing to serve.
+fn redirection_outcome(status: u16, has_stale: bool) -> Redirection {
+    if status == 304 && has_stale {
+        Redirection::Revalidated
+    } else {
+        Redirection::Fallback
+    }
+}
+
+/// The host of `url_str`, or an empty string when it does not parse.
+fn url_host(url_str: &str) -> String {
+    Url::parse(url_str)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn is_permitted_domain(ctx: &Shared, url_str: &str) -> bool {
+    let host = url_host(url_str);
+    domains::is_permitted(
+        &host,
+        &var_list(&ctx.env, "ALLOWED_DOMAINS", ""),
+        &var_list(&ctx.env, "BLOCKED_DOMAINS", ""),
+    )
+}
+
+/// Returns why `url_str` must not be fetched, if it must not.
+fn blocked_reason(ctx: &Shared, url_str: &str) -> Option<String> {
+    let allowed_hosts = var_list(&ctx.env, "ALLOWED_HOSTS", "");
+    ssrf::check(url_str, &allowed_hosts).err()
+}
+
+fn query_param(req: &Request, name: &str) -> Result<Option<String>> {
+    Ok(req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.into_owned()))
+}
+
+/// Grace period after `exp` during which a signed URL is still accepted, so that clocks
+/// slightly ahead of the worker's do not mint already-expired links.
+const SIGNATURE_CLOCK_SKEW_SECS: u64 = 60;
+
+/// Decodes a base64url `url_b64` parameter, with or without padding.
+fn decode_url_b64(encoded: &str) -> Option<String> {
+    const URL_SAFE_INDIFFERENT: GeneralPurpose = GeneralPurpose::new(
+        &alphabet::URL_SAFE,
+        GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+    );
+    let bytes = URL_SAFE_INDIFFERENT.decode(encoded.trim()).ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Loads the configured API tokens as `(label, token)` pairs.
+///
+/// `API_TOKENS` is either a JSON object mapping labels to tokens or a comma-separated list
+/// of `label:token` entries (a bare token is labelled by its position). The legacy
+/// `API_TOKEN` is accepted under the label `default`.
+fn api_tokens(ctx: &Shared) -> Result<Vec<(String, String)>> {
+    let mut tokens = Vec::new();
+    if let Ok(api_token) = ctx.env.var("API_TOKEN") {
+        tokens.push(("default".to_string(), api_token.to_string()));
+    }
+    if let Ok(api_tokens) = ctx.env.var("API_TOKENS") {
+        let api_tokens = api_tokens.to_string();
+        if api_tokens.trim_start().starts_with('{') {
+            let labelled: HashMap<String, String> = serde_json::from_str(&api_tokens)
+                .map_err(|e| Error::from(format!("invalid API_TOKENS: {e}")))?;
+            tokens.extend(labelled);
+        } else {
+            for (i, entry) in api_tokens.split(',').map(str::trim).enumerate() {
+                match entry.split_once(':') {
+                    Some((label, token)) => tokens.push((label.to_string(), token.to_string())),
+                    None if !entry.is_empty() => {
+                        tokens.push((format!("token-{i}"), entry.to_string()))
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+    if tokens.is_empty() {
+        return Err(Error::from("API_TOKEN or API_TOKENS must be configured"));
+    }
+    Ok(tokens)
+}
+
+/// Checks `token` against every configured API token, logging the label that matched.
+fn is_valid_token(ctx: &Shared, token: &str) -> Result<bool> {
+    let mut label = None;
+    for (candidate_label, candidate) in api_tokens(ctx)? {
+        // keep comparing after a match so timing does not reveal which token matched
+        if signing::constant_time_eq(token.as_bytes(), candidate.as_bytes()) && label.is_none() {
+            label = Some(candidate_label);
+        }
+    }
+    match label {
+        Some(label) => {
+            tracing::info!(token_label = label, "authenticated request");
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// The `BASIC_AUTH_USER`/`BASIC_AUTH_PASS` pair `GET /` requires, if either is set.
+fn basic_auth_credentials(ctx: &Shared) -> Option<(String, String)> {
+    let user = ctx.env.var("BASIC_AUTH_USER").map(|v| v.to_string()).ok();
+    let pass = ctx.env.var("BASIC_AUTH_PASS").map(|v| v.to_string()).ok();
+    if user.is_none() && pass.is_none() {
+        return None;
+    }
+    Some((user.unwrap_or_default(), pass.unwrap_or_default()))
+}
+
+/// `401` asking the client to retry with Basic credentials.
+fn unauthorized(headers: &Headers) -> Result<Response> {
+    let res = errors::respond(headers, 401, "UNAUTHORIZED", "authentication required")?;
+    res.headers().set(
+        "WWW-Authenticate",
+        r#"Basic realm="freshrss-image-cache-proxy", charset="UTF-8""#,
+    )?;
+    Ok(res)
+}
+
+/// Whether the client asked to be served only from cache, with `X-Prefer-Cache: true` or
+/// `Cache-Control: only-if-cached`.
+fn prefers_cache(headers: &Headers) -> Result<bool> {
+    if headers
+        .get("X-Prefer-Cache")?
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+    {
+        return Ok(true);
+    }
+    Ok(headers.get("Cache-Control")?.is_some_and(|value| {
+        value
+            .split(',')
+            .any(|directive| directive.trim().eq_ignore_ascii_case("only-if-cached"))
+    }))
+}
+
+#[tracing::instrument(err, skip(route))]
+async fn get(req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let url = match query_param(&req, "url_b64")? {
+        Some(encoded) => match decode_url_b64(&encoded) {
+            Some(url) => url,
+            None => {
+                return errors::bad_request(
+                    req.headers(),
+                    "INVALID_PARAMETER",
+                    "invalid url_b64 parameter",
+                )
+            }
+        },
+        None => match query_param(&req, "url")? {
+            Some(url) => url,
+            None => return errors::missing_param(req.headers(), "url"),
+        },
+    };
+    let refresh = matches!(
+        query_param(&req, "refresh")?.as_deref(),
+        Some("1" | "true" | "no-cache")
+    );
+    let ttl = query_param(&req, "ttl")?;
+    let signed = ctx.env.var("SIGNING_KEY").is_ok();
+    let mut basic_authenticated = false;
+    if let Some((user, pass)) = basic_auth_credentials(&ctx) {
+        let authorization = req.headers().get("Authorization")?;
+        basic_authenticated =
+            authorization.is_some_and(|header| signing::verify_basic_auth(&header, &user, &pass));
+        // with signing also configured, a signature is an accepted alternative
+        let has_sig = signed && query_param(&req, "sig")?.is_some();
+        if !basic_authenticated && !has_sig {
+            tracing::warn!(url = url, "rejected request without valid basic auth");
+            return unauthorized(req.headers());
+        }
+    }
+    // basic auth vouches for the whole request, so signature checks are skipped
+    let signing_key = ctx.env.var("SIGNING_KEY").ok();
+    if let Some(signing_key) = signing_key.filter(|_| !basic_authenticated) {
+        let sig = query_param(&req, "sig")?.unwrap_or_default();
+        let exp = query_param(&req, "exp")?;
+        let mut message = match &exp {
+            Some(exp) => format!("{url}|{exp}"),
+            None => url.clone(),
+        };
+        if let Some(ttl) = &ttl {
+            message.push_str(&format!("|ttl={ttl}"));
+        }
+        if !signing::verify(&signing_key.to_string(), &message, &sig) {
+            tracing::warn!(
+                url = url,
+                "rejected request with missing or invalid signature"
+            );
+            return errors::forbidden(req.headers(), "INVALID_SIGNATURE", "invalid signature");
+        }
+        if let Some(exp) = exp {
+            let Ok(exp) = exp.parse::<u64>() else {
+                return errors::bad_request(
+                    req.headers(),
+                    "INVALID_PARAMETER",
+                    "invalid exp parameter",
+                );
+            };
+            if now_secs() > exp + SIGNATURE_CLOCK_SKEW_SECS {
+                tracing::warn!(
+                    url = url,
+                    exp = exp,
+                    "rejected request with expired signature"
+                );
+                return errors::forbidden(req.headers(), "SIGNATURE_EXPIRED", "signature expired");
+            }
+        }
+        // the signature only covers `url`, so refreshing additionally needs a token
+        if refresh {
+            let token = query_param(&req, "access_token")?.unwrap_or_default();
+            if !is_valid_token(&ctx, &token)? {
+                return errors::invalid_token(req.headers());
+            }
+        }
+    }
+    let ttl = match ttl {
+        Some(ttl) => {
+            let Ok(ttl) = ttl.parse::<u64>() else {
+                return errors::bad_request(
+                    req.headers(),
+                    "INVALID_PARAMETER",
+                    "invalid ttl parameter",
+                );
+            };
+            // without signing, only token holders may choose how long objects live
+            if !signed && !basic_authenticated {
+                let token = query_param(&req, "access_token")?.unwrap_or_default();
+                if !is_valid_token(&ctx, &token)? {
+                    return errors::forbidden(
+                        req.headers(),
+                        "TTL_NOT_AUTHORIZED",
+                        "ttl requires a signature or access token",
+                    );
+                }
+            }
+            Some(ttl)
+        }
+        None => None,
+    };
+    // after the signature check, which covers the URL as FreshRSS sent it
+    let url = unwrapped_url(&ctx.env, &url);
+    if let Some(reason) = blocked_reason(&ctx, &url) {
+        tracing::warn!(url = url, reason = reason, "rejected url");
+        return errors::bad_request(req.headers(), "BLOCKED_URL", reason);
+    }
+    let opts = RequestOptions {
+        fallback_type: query_param(&req, "type")?,
+        refresh,
+        ttl,
+        only_cached: prefers_cache(req.headers())?,
+        tag: cache_tag(req.headers())?,
+        ..Default::default()
+    };
+    let res = cache_url(&ctx, &url, req.headers(), &opts).await?;
+    // responses passed through from the origin carry immutable headers
+    let headers = res.headers().clone();
+    headers.append("Vary", "Accept")?;
+    Ok(res.with_headers(headers))
+}
+
+/// Default for `MAX_POST_BODY_BYTES`; a full `/batch` request is far smaller.
+const DEFAULT_MAX_POST_BODY_BYTES: usize = 1024 * 1024;
+
+/// Parses the JSON body of `req`, or returns the error response to send instead: `413`
+/// past `MAX_POST_BODY_BYTES` and `400` when it is not valid JSON of the expected shape.
+async fn read_json_body<T: serde::de::DeserializeOwned>(
+    ctx: &Shared,
+    req: &mut Request,
+) -> Result<std::result::Result<T, Response>> {
+    let max_bytes =
+        var_parse(&ctx.env, "MAX_POST_BODY_BYTES")?.unwrap_or(DEFAULT_MAX_POST_BODY_BYTES);
+    let headers = req.headers().clone();
+    let too_large = || {
+        errors::payload_too_large(
+            &headers,
+            "BODY_TOO_LARGE",
+            format!("body exceeds {max_bytes} bytes"),
+        )
+    };
+    let content_length: Option<usize> = req
+        .headers()
+        .get("Content-Length")?
+        .and_then(|length| length.parse().ok());
+    if content_length.is_some_and(|length| length > max_bytes) {
+        return too_large().map(Err);
+    }
+    // chunked bodies carry no Content-Length, so the size is checked again once read
+    let bytes = req.bytes().await?;
+    if bytes.len() > max_bytes {
+        return too_large().map(Err);
+    }
+    match serde_json::from_slice(&bytes) {
+        Ok(body) => Ok(Ok(body)),
+        Err(e) => errors::bad_request(
+            &headers,
+            "INVALID_BODY",
+            format!("invalid request body: {e}"),
+        )
+        .map(Err),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct PostRequest {
+    url: String,
+    access_token: String,
+    /// Reports what would be cached instead of caching it.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[tracing::instrument(err, skip(route))]
+async fn post(mut req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let body: PostRequest = match read_json_body(&ctx, &mut req).await? {
+        Ok(body) => body,
+        Err(res) => return Ok(res),
+    };
+    if !is_valid_token(&ctx, &body.access_token)? {
+        return errors::invalid_token(req.headers());
+    }
+    if body.dry_run {
+        return Response::from_json(&dry_run_url(&ctx, body.url, req.headers()).await);
+    }
+    if let Some(reason) = blocked_reason(&ctx, &body.url) {
+        tracing::warn!(url = body.url, reason = reason, "rejected url");
+        return errors::bad_request(req.headers(), "BLOCKED_URL", reason);
+    }
+    let opts = RequestOptions {
+        tag: cache_tag(req.headers())?,
+        ..Default::default()
+    };
+    cache_url(&ctx, &body.url, req.headers(), &opts).await?;
+    Response::empty()
+}
+
+/// Default cap on the number of URLs accepted by `POST /batch`.
+const DEFAULT_MAX_BATCH_URLS: usize = 50;
+
+/// Origin fetches run at once by `POST /batch`, below the Workers simultaneous
+/// connection limit.
+const BATCH_CONCURRENCY: usize = 6;
+
+#[derive(serde::Deserialize)]
+struct BatchRequest {
+    urls: Vec<String>,
+    access_token: String,
+    /// Reports what would be cached instead of caching it.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum DryRunOutcome {
+    WouldCache,
+    AlreadyPresent,
+    Rejected,
+}
+
+/// What warming a URL would do, as predicted from a `HEAD` to its origin.
+#[derive(serde::Serialize)]
+struct DryRunResult {
+    url: String,
+    outcome: DryRunOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_length: Option<u64>,
+}
+
+impl DryRunResult {
+    fn new(url: String, outcome: DryRunOutcome) -> Self {
+        Self {
+            url,
+            outcome,
+            reason: None,
+            content_type: None,
+            content_length: None,
+        }
+    }
+
+    fn rejected(url: String, reason: impl Into<String>) -> Self {
+        Self {
+            reason: Some(reason.into()),
+            ..Self::new(url, DryRunOutcome::Rejected)
+        }
+    }
+}
+
+/// Runs the checks warming `url` would, without writing to R2 or downloading the body.
+async fn dry_run_url(ctx: &Shared, url: String, headers: &Headers) -> DryRunResult {
+    if let Some(reason) = blocked_reason(ctx, &url) {
+        return DryRunResult::rejected(url, reason);
+    }
+    if !is_permitted_domain(ctx, &url) {
+        return DryRunResult::rejected(url, "domain is not allowed");
+    }
+    // `None` when an unexpired copy is already stored
+    let checked: Result<Option<(Response, Option<u64>)>> = async {
+        if let Some(existing) = head_from_r2(ctx, &url, headers).await? {
+            if !is_expired(&existing)? {
+                return Ok(None);
+            }
+        }
+        let timeout_ms = var_parse(&ctx.env, "FETCH_TIMEOUT_MS")?;
+        let res = send_following_redirects(
+            ctx,
+            &url,
+            worker::Method::Head,
+            headers,
+            &Validators::default(),
+            timeout_ms,
+        )
+        .await?;
+        Ok(Some((res, var_parse(&ctx.env, "MAX_OBJECT_BYTES")?)))
+    }
+    .await;
+    let (res, max_bytes) = match checked {
+        Ok(Some(checked)) => checked,
+        Ok(None) => return DryRunResult::new(url, DryRunOutcome::AlreadyPresent),
+        Err(e) => return DryRunResult::rejected(url, format!("origin check failed: {e}")),
+    };
+    if !(200..300).contains(&res.status_code()) {
+        return DryRunResult::rejected(url, format!("origin returned {}", res.status_code()));
+    }
+    let content_type = res.headers().get("Content-Type").ok().flatten();
+    let content_length: Option<u64> = res
+        .headers()
+        .get("Content-Length")
+        .ok()
+        .flatten()
+        .and_then(|length| length.parse().ok());
+    let reason = if !is_allowed_content_type(ctx, content_type.as_deref().unwrap_or_default()) {
+        Some("content type is not allowed".to_string())
+    } else if content_length == Some(0) {
+        Some("body is empty".to_string())
+    } else if let (Some(length), Some(max)) = (content_length, max_bytes) {
+        (length > max).then(|| format!("body exceeds MAX_OBJECT_BYTES ({length} > {max})"))
+    } else {
+        None
+    };
+    DryRunResult {
+        url,
+        outcome: match reason {
+            Some(_) => DryRunOutcome::Rejected,
+            None => DryRunOutcome::WouldCache,
+        },
+        reason,
+        content_type,
+        content_length,
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum WarmStatus {
+    Cached,
+    AlreadyPresent,
+    Error,
+}
+
+#[derive(serde::Serialize)]
+struct WarmResult {
+    url: String,
+    status: WarmStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl WarmResult {
+    fn error(url: String, error: impl Into<String>) -> Self {
+        Self {
+            url,
+            status: WarmStatus::Error,
+            error: Some(error.into()),
+        }
+    }
+}
+
+/// Caches `url` and reports whether it ended up in R2.
+async fn warm_url(ctx: &Shared, url: String, headers: &Headers) -> WarmResult {
+    if let Some(reason) = blocked_reason(ctx, &url) {
+        return WarmResult::error(url, reason);
+    }
+    if !is_permitted_domain(ctx, &url) {
+        return WarmResult::error(url, "domain is not allowed");
+    }
+    let result = async {
+        if let Some(existing) = head_from_r2(ctx, &url, headers).await? {
+            if !is_expired(&existing)? {
+                return Ok(WarmStatus::AlreadyPresent);
+            }
+        }
+        let opts = RequestOptions {
+            await_put: true,
+            tag: cache_tag(headers)?,
+            ..Default::default()
+        };
+        cache_url(ctx, &url, headers, &opts).await?;
+        match head_from_r2(ctx, &url, headers).await? {
+            Some(_) => Ok(WarmStatus::Cached),
+            None => Err(Error::from("origin response was not cacheable")),
+        }
+    };
+    match result.await {
+        Ok(status) => WarmResult {
+            url,
+            status,
+            error: None,
+        },
+        Err(e) => WarmResult::error(url, e.to_string()),
+    }
+}
+
+#[tracing::instrument(err, skip(route))]
+async fn batch(mut req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let body: BatchRequest = match read_json_body(&ctx, &mut req).await? {
+        Ok(body) => body,
+        Err(res) => return Ok(res),
+    };
+    if !is_valid_token(&ctx, &body.access_token)? {
+        return errors::invalid_token(req.headers());
+    }
+    let max_urls = var_parse(&ctx.env, "MAX_BATCH_URLS")?.unwrap_or(DEFAULT_MAX_BATCH_URLS);
+    if body.urls.len() > max_urls {
+        return errors::bad_request(
+            req.headers(),
+            "TOO_MANY_URLS",
+            format!("too many urls, at most {max_urls} allowed"),
+        );
+    }
+    let headers = req.headers();
+    if body.dry_run {
+        let results: Vec<DryRunResult> = futures::stream::iter(body.urls)
+            .map(|url| dry_run_url(&ctx, url, headers))
+            .buffered(BATCH_CONCURRENCY)
+            .collect()
+            .await;
+        return Response::from_json(&results);
+    }
+    let results: Vec<WarmResult> = futures::stream::iter(body.urls)
+        .map(|url| warm_url(&ctx, url, headers))
+        .buffered(BATCH_CONCURRENCY)
+        .collect()
+        .await;
+    Response::from_json(&results)
+}
+
+/// Largest feed document `POST /warm-feed` will download.
+const MAX_FEED_BYTES: u64 = 5 * 1024 * 1024;
+
+#[derive(serde::Deserialize)]
+struct WarmFeedRequest {
+    feed_url: String,
+    access_token: String,
+}
+
+#[derive(Default, serde::Serialize)]
+struct WarmFeedResult {
+    discovered: usize,
+    cached: usize,
+    already_present: usize,
+    failed: usize,
+    /// Images outside `ALLOWED_DOMAINS` or in `BLOCKED_DOMAINS`, which were not attempted.
+    rejected: usize,
+    /// Images beyond `MAX_BATCH_URLS` that were not attempted.
+    skipped: usize,
+}
+
+#[tracing::instrument(err, skip(route))]
+async fn warm_feed(mut req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let body: WarmFeedRequest = match read_json_body(&ctx, &mut req).await? {
+        Ok(body) => body,
+        Err(res) => return Ok(res),
+    };
+    if !is_valid_token(&ctx, &body.access_token)? {
+        return errors::invalid_token(req.headers());
+    }
+    if let Some(reason) = blocked_reason(&ctx, &body.feed_url) {
+        tracing::warn!(url = body.feed_url, reason = reason, "rejected feed url");
+        return errors::bad_request(req.headers(), "BLOCKED_URL", reason);
+    }
+    let feed_headers = Headers::new();
+    feed_headers.set(
+        "Accept",
+        "application/rss+xml, application/atom+xml, application/xml;q=0.9, */*;q=0.8",
+    )?;
+    let mut res =
+        fetch_with_retry(&ctx, &body.feed_url, &feed_headers, &Validators::default()).await?;
+    if res.status_code() != 200 {
+        return errors::bad_gateway(
+            req.headers(),
+            "FEED_UNAVAILABLE",
+            format!("feed responded with status {}", res.status_code()),
+        );
+    }
+    let max_ratio = max_decomp_ratio(&ctx)?;
+    let read = read_body_limited(&body.feed_url, &mut res, Some(MAX_FEED_BYTES), max_ratio);
+    let Ok(document) = read.await? else {
+        return errors::bad_gateway(req.headers(), "FEED_TOO_LARGE", "feed is too large");
+    };
+    let base = Url::parse(&body.feed_url)?;
+    let mut urls = feed::image_urls(&String::from_utf8_lossy(&document), &base);
+    let mut summary = WarmFeedResult {
+        discovered: urls.len(),
+        ..Default::default()
+    };
+    // filtered first, so that they do not use up `MAX_BATCH_URLS`
+    urls.retain(|url| is_permitted_domain(&ctx, url));
+    summary.rejected = summary.discovered - urls.len();
+    let max_urls = var_parse(&ctx.env, "MAX_BATCH_URLS")?.unwrap_or(DEFAULT_MAX_BATCH_URLS);
+    summary.skipped = urls.len().saturating_sub(max_urls);
+    urls.truncate(max_urls);
+    let headers = req.headers();
+    let results: Vec<WarmResult> = futures::stream::iter(urls)
+        .map(|url| warm_url(&ctx, url, headers))
+        .buffered(BATCH_CONCURRENCY)
+        .collect()
+        .await;
+    for result in results {
+        match result.status {
+            WarmStatus::Cached => summary.cached += 1,
+            WarmStatus::AlreadyPresent => summary.already_present += 1,
+            WarmStatus::Error => summary.failed += 1,
+        }
+    }
+    tracing::info!(
+        url = body.feed_url,
+        discovered = summary.discovered,
+        cached = summary.cached,
+        failed = summary.failed,
+        rejected = summary.rejected,
+        "warmed images from feed",
+    );
+    Response::from_json(&summary)
+}
+
+/// Days of per-day counters returned by `GET /stats` by default.
+const DEFAULT_STATS_DAYS: u32 = 7;
+
+/// Upper bound for the `days` parameter of `GET /stats`, each day being one KV read.
+const MAX_STATS_DAYS: u32 = 90;
+
+#[derive(serde::Serialize)]
+struct StatsResponse {
+    #[serde(flatten)]
+    stats: stats::Stats,
+    /// Origin fetches running in the isolate that answered, not across the deployment.
+    in_flight_fetches: usize,
+    read_only: bool,
+}
+
+#[tracing::instrument(err, skip(route))]
+async fn get_stats(req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let token = query_param(&req, "access_token")?.unwrap_or_default();
+    if !is_valid_token(&ctx, &token)? {
+        return errors::invalid_token(req.headers());
+    }
+    let days = match query_param(&req, "days")? {
+        Some(days) => match days.parse() {
+            Ok(days) => days,
+            Err(_) => {
+                return errors::bad_request(
+                    req.headers(),
+                    "INVALID_PARAMETER",
+                    "invalid days parameter",
+                )
+            }
+        },
+        None => DEFAULT_STATS_DAYS,
+    }
+    .min(MAX_STATS_DAYS);
+    let kv = ctx.env.kv("STATS_KV")?;
+    Response::from_json(&StatsResponse {
+        stats: stats::load(&kv, days).await?,
+        in_flight_fetches: limiter::in_flight(),
+        read_only: is_read_only(&ctx.env),
+    })
+}
+
+/// The counters of `GET /stats` in Prometheus text format, for scrapers authenticating
+/// with `Authorization: Bearer <METRICS_TOKEN>`.
+#[tracing::instrument(err, skip(route))]
+async fn metrics(req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    // disabled rather than open, as the counters reveal what the instance is used for
+    let Ok(expected) = ctx.env.var("METRICS_TOKEN") else {
+        return errors::not_found(req.headers(), "NOT_CONFIGURED", "metrics are disabled");
+    };
+    let authorization = req.headers().get("Authorization")?.unwrap_or_default();
+    let token = authorization.strip_prefix("Bearer ").unwrap_or_default();
+    if !signing::constant_time_eq(token.as_bytes(), expected.to_string().as_bytes()) {
+        return errors::invalid_token(req.headers());
+    }
+    let kv = ctx.env.kv("STATS_KV")?;
+    let body = stats::load_total(&kv).await?.to_prometheus();
+    let res = Response::ok(body)?;
+    res.headers()
+        .set("Content-Type", "text/plain; version=0.0.4")?;
+    Ok(res)
+}
+
+#[derive(serde::Deserialize)]
+struct GcRequest {
+    access_token: String,
+    /// The `cursor` returned by the previous run, to continue where it stopped.
+    #[serde(default)]
+    cursor: Option<String>,
+    /// `hot` (the default) or `cold`; each tier is scanned with its own cursor.
+    #[serde(default)]
+    tier: Option<String>,
+}
+
+/// Deletes R2 objects that have not been served for `GC_MAX_AGE_SECONDS`. Large buckets
+/// take several calls; each returns a `cursor` to pass to the next until none is left.
+#[tracing::instrument(err, skip(route))]
+async fn gc(mut req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let body: GcRequest = match read_json_body(&ctx, &mut req).await? {
+        Ok(body) => body,
+        Err(res) => return Ok(res),
+    };
+    if !is_valid_token(&ctx, &body.access_token)? {
+        return errors::invalid_token(req.headers());
+    }
+    let Some(max_age) = var_parse(&ctx.env, "GC_MAX_AGE_SECONDS")? else {
+        return errors::bad_request(
+            req.headers(),
+            "NOT_CONFIGURED",
+            "GC_MAX_AGE_SECONDS is not set",
+        );
+    };
+    let prefix = key_namespace(&ctx.env).map(|namespace| format!("{namespace}/"));
+    let bucket = ctx.tier_bucket(body.tier.as_deref().unwrap_or(HOT_TIER))?;
+    let summary = gc::collect(&bucket, prefix, body.cursor, max_age).await?;
+    tracing::info!(summary = ?summary, "garbage collection run finished");
+    Response::from_json(&summary)
+}
+
+#[derive(serde::Deserialize)]
+struct PinRequest {
+    url: String,
+    access_token: String,
+}
+
+/// Sets or clears the pin on every stored variant of the requested URL, `404` when none
+/// is cached.
+async fn set_pinned(
+    mut req: Request,
+    route: RouteContext<Rc<Context>>,
+    pinned: bool,
+) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let body: PinRequest = match read_json_body(&ctx, &mut req).await? {
+        Ok(body) => body,
+        Err(res) => return Ok(res),
+    };
+    if !is_valid_token(&ctx, &body.access_token)? {
+        return errors::invalid_token(req.headers());
+    }
+    let keys = all_r2_keys(&ctx.env, &body.url);
+    let value = pinned.then(|| "true".to_string());
+    let mut found = false;
+    for key in keys {
+        if gc::set_metadata_field(&ctx, &key, gc::PINNED_FIELD, value.clone()).await? {
+            tracing::info!(url = body.url, key = key, pinned = pinned, "updated pin");
+            found = true;
+        }
+    }
+    if !found {
+        return errors::not_found(req.headers(), "NOT_CACHED", "object not found");
+    }
+    Response::empty()
+}
+
+/// Exempts a cached URL from `POST /gc`.
+#[tracing::instrument(err, skip(route))]
+async fn pin(req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    set_pinned(req, route, true).await
+}
+
+/// Makes a pinned URL eligible for `POST /gc` again.
+#[tracing::instrument(err, skip(route))]
+async fn unpin(req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    set_pinned(req, route, false).await
+}
+
+/// Key probed by `/health`; it does not need to exist.
+const HEALTH_SENTINEL_KEY: &str = "health/sentinel";
+
+#[derive(serde::Serialize)]
+struct HealthCheck {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl<T> From<Result<T>> for HealthCheck {
+    fn from(result: Result<T>) -> Self {
+        match result {
+            Ok(_) => Self {
+                ok: true,
+                error: None,
+            },
+            Err(e) => Self {
+                ok: false,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Health {
+    ok: bool,
+    /// Reported rather than checked: a read-only deployment is still healthy.
+    read_only: bool,
+    checks: BTreeMap<&'static str, HealthCheck>,
+}
+
+#[tracing::instrument(err, skip(route))]
+async fn health(_req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let mut checks = BTreeMap::new();
+    // the fallback URL is not used when the worker serves the fallback image itself
+    let fallback_mode = ctx.env.var("FALLBACK_MODE").map(|v| v.to_string());
+    if fallback_mode.as_deref().ok() != Some("pixel") {
+        checks.insert(
+            "FALLBACK_URL",
+            HealthCheck::from(ctx.env.var("FALLBACK_URL")),
+        );
+    }
+    checks.insert("API_TOKEN", HealthCheck::from(api_tokens(&ctx)));
+    let r2 = async { ctx.bucket("R2_BINDING")?.head(HEALTH_SENTINEL_KEY).await }.await;
+    checks.insert("R2_BINDING", HealthCheck::from(r2));
+    if ctx.bucket("R2_COLD_BINDING").is_ok() {
+        let cold = async {
+            ctx.bucket("R2_COLD_BINDING")?
+                .head(HEALTH_SENTINEL_KEY)
+                .await
+        }
+        .await;
+        checks.insert("R2_COLD_BINDING", HealthCheck::from(cold));
+    }
+    let ok = checks.values().all(|check| check.ok);
+    if !ok {
+        tracing::warn!("health check failed");
+    }
+    let res = Response::from_json(&Health {
+        ok,
+        read_only: is_read_only(&ctx.env),
+        checks,
+    })?;
+    Ok(if ok { res } else { res.with_status(503) })
+}
+
+/// Where `GET /selftest` stores its copy, away from the keys of cached images.
+const SELFTEST_KEY: &str = "selftest/object";
+
+/// [`SELFTEST_KEY`] under `KEY_NAMESPACE`, so that deployments sharing a bucket do not
+/// test each other's copy.
+fn selftest_r2_key(env: &Env) -> String {
+    match key_namespace(env) {
+        Some(namespace) => format!("{namespace}/{SELFTEST_KEY}"),
+        None => SELFTEST_KEY.to_string(),
+    }
+}
+
+#[derive(serde::Serialize)]
+struct SelfTestStep {
+    step: &'static str,
+    ok: bool,
+    ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct SelfTest {
+    ok: bool,
+    url: String,
+    steps: Vec<SelfTestStep>,
+}
+
+impl SelfTest {
+    /// Runs `step`, recording how it went and how long it took.
+    async fn run<T>(
+        &mut self,
+        step: &'static str,
+        fut: impl std::future::Future<Output = Result<T>>,
+    ) -> Option<T> {
+        let started = Date::now().as_millis();
+        let result = fut.await;
+        let ms = Date::now().as_millis() - started;
+        let (ok, error) = match &result {
+            Ok(_) => (true, None),
+            Err(e) => (false, Some(e.to_string())),
+        };
+        self.ok &= ok;
+        self.steps.push(SelfTestStep {
+            step,
+            ok,
+            ms,
+            error,
+        });
+        result.ok()
+    }
+}
+
+/// Fetches `url` (or `SELFTEST_URL`), stores it with [`put_in_r2`] under
+/// [`selftest_r2_key`], serves it back with [`serve_cached`] comparing body and headers,
+/// then deletes it again.
+#[tracing::instrument(err, skip(route))]
+async fn selftest(req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let token = query_param(&req, "access_token")?.unwrap_or_default();
+    if !is_valid_token(&ctx, &token)? {
+        return errors::invalid_token(req.headers());
+    }
+    let url = match query_param(&req, "url")? {
+        Some(url) => url,
+        None => match ctx.env.var("SELFTEST_URL") {
+            Ok(url) => url.to_string(),
+            Err(_) => return errors::missing_param(req.headers(), "url"),
+        },
+    };
+    if let Some(reason) = blocked_reason(&ctx, &url) {
+        return errors::bad_request(req.headers(), "BLOCKED_URL", reason);
+    }
+    let mut report = SelfTest {
+        ok: true,
+        url: url.clone(),
+        steps: Vec::new(),
+    };
+    let key = selftest_r2_key(&ctx.env);
+    let fetched = report
+        .run("fetch", async {
+            // the caller's headers are not the ones FreshRSS would send
+            let mut res =
+                fetch_with_retry(&ctx, &url, &Headers::new(), &Validators::default()).await?;
+            if res.status_code() != 200 {
+                return Err(Error::from(format!(
+                    "origin responded with status {}",
+                    res.status_code()
+                )));
+            }
+            let max_bytes = var_parse(&ctx.env, "MAX_OBJECT_BYTES")?;
+            let body = read_body_limited(&url, &mut res, max_bytes, max_decomp_ratio(&ctx)?)
+                .await?
+                .map_err(|_| Error::from("origin body exceeds MAX_OBJECT_BYTES"))?;
+            Ok((res.headers().clone(), body))
+        })
+        .await;
+    if let Some((headers, body)) = fetched {
+        let options = PutOptions {
+            overwrite: true,
+            key: Some(key.clone()),
+            ..PutOptions::default()
+        };
+        let stored = report
+            .run(
+                "store",
+                put_in_r2(&ctx, &url, None, &headers, body.clone(), &options),
+            )
+            .await;
+        if stored.is_some() {
+            report
+                .run("serve", serve_selftest(&ctx, &key, &url, &headers, &body))
+                .await;
+        }
+        // attempted even after a failed store, which may still have written something
+        report
+            .run("delete", async {
+                for (_, bucket) in ctx.buckets()? {
+                    bucket.delete(&key).await?;
+                }
+                Ok(())
+            })
+            .await;
+    }
+    if !report.ok {
+        tracing::warn!(url = url, "self test failed");
+    }
+    let res = Response::from_json(&report)?;
+    Ok(if report.ok { res } else { res.with_status(500) })
+}
+
+/// Serves the self-test copy under `key` as a client without `Accept-Encoding` would get
+/// it, and checks it against the origin's `headers` and `body`.
+async fn serve_selftest(
+    ctx: &Shared,
+    key: &str,
+    url: &str,
+    headers: &Headers,
+    body: &[u8],
+) -> Result<()> {
+    let mut obj = None;
+    for (_, bucket) in ctx.buckets()? {
+        obj = bucket.get(key).execute().await?;
+        if obj.is_some() {
+            break;
+        }
+    }
+    let Some(obj) = obj else {
+        return Err(Error::from("stored object not found"));
+    };
+    let metadata = obj.custom_metadata()?;
+    if metadata.get("url").map(String::as_str) != Some(origin_url(&ctx.env, url).as_str()) {
+        return Err(Error::from("stored url differs from the one fetched"));
+    }
+    let Some(mut res) = serve_cached(ctx, &obj, &Headers::new()).await? else {
+        return Err(Error::from("stored object has no body"));
+    };
+    // with `SERVE_MODE=redirect` the public endpoint serves the body, not this worker
+    if res.status_code() == 302 {
+        return match res.headers().get("Location")? {
+            Some(_) => Ok(()),
+            None => Err(Error::from("redirect without a Location")),
+        };
+    }
+    if res.status_code() != 200 {
+        return Err(Error::from(format!(
+            "cached copy served with status {}",
+            res.status_code()
+        )));
+    }
+    let content_type = headers.get("Content-Type")?;
+    if content_type.is_some() && res.headers().get("Content-Type")? != content_type {
+        return Err(Error::from("Content-Type differs from the origin's"));
+    }
+    // `Cache-Control` is rewritten from the stored TTL rather than replayed
+    for (_, header) in METADATA_HEADERS
+        .iter()
+        .filter(|(_, h)| *h != "Cache-Control")
+    {
+        let Some(value) = headers.get(header)? else {
+            continue;
+        };
+        if res.headers().get(header)? != Some(sanitize_header_value(&value)) {
+            return Err(Error::from(format!("{header} was not replayed")));
+        }
+    }
+    let expected = if metadata.contains_key("svg_sanitized") {
+        svg::sanitize(body)
+    } else {
+        body.to_vec()
+    };
+    if res.bytes().await? != expected {
+        return Err(Error::from("served body differs from the origin's"));
+    }
+    Ok(())
+}
+
+#[tracing::instrument(err, skip(route))]
+async fn head(req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let Some(url) = query_param(&req, "url")? else {
+        return errors::missing_param(req.headers(), "url");
+    };
+    let Some(obj) = head_from_r2(&ctx, &url, req.headers()).await? else {
+        let res = Response::empty()?.with_status(404);
+        res.headers().set("X-Cache", "MISS")?;
+        return Ok(res);
+    };
+    let res = Response::empty()?;
+    res.headers().set("X-Cache", "HIT")?;
+    let metadata = obj.custom_metadata()?;
+    let accepts_gzip = compress::accepts_gzip(req.headers().get("Accept-Encoding")?.as_deref());
+    let (content_length, content_encoding) = served_encoding(&metadata, obj.size(), accepts_gzip);
+    res.headers().set("Content-Length", &content_length)?;
+    if metadata.contains_key("content_encoding") {
+        res.headers().set("Vary", "Accept-Encoding")?;
+    }
+    if let Some(content_encoding) = content_encoding {
+        res.headers().set("Content-Encoding", content_encoding)?;
+    }
+    if let Some(content_type) = metadata.get("content_type") {
+        res.headers().set("Content-Type", content_type)?;
+    }
+    for (field, header) in DERIVED_HEADERS {
+        if let Some(value) = metadata.get(*field) {
+            res.headers().set(header, value)?;
+        }
+    }
+    Ok(res)
+}
+
+/// The `Content-Length` and `Content-Encoding` a `GET` of an object of `size` stored bytes
+/// would be sent with: [`serve_gzipped`] decodes gzipped objects for clients without
+/// `accepts_gzip`, so they get the length recorded before compression.
+fn served_encoding(
+    metadata: &HashMap<String, String>,
+    size: u64,
+    accepts_gzip: bool,
+) -> (String, Option<&'static str>) {
+    let stored = size.to_string();
+    if metadata.get("content_encoding").map(String::as_str) != Some("gzip") {
+        return (stored, None);
+    }
+    if accepts_gzip {
+        return (stored, Some("gzip"));
+    }
+    (
+        metadata.get("content_length").cloned().unwrap_or(stored),
+        None,
+    )
+}
+
+#[derive(serde::Serialize)]
+struct Placeholder {
+    blurhash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+}
+
+#[tracing::instrument(err, skip(route))]
+async fn placeholder(req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let Some(url) = query_param(&req, "url")? else {
+        return errors::missing_param(req.headers(), "url");
+    };
+    let Some(obj) = head_from_r2(&ctx, &url, req.headers()).await? else {
+        return errors::not_found(req.headers(), "NOT_CACHED", "object not found");
+    };
+    let metadata = obj.custom_metadata()?;
+    let Some(blurhash) = metadata.get("blurhash") else {
+        return errors::not_found(req.headers(), "NO_PLACEHOLDER", "no placeholder for object");
+    };
+    Response::from_json(&Placeholder {
+        blurhash: blurhash.clone(),
+        width: metadata.get("width").and_then(|w| w.parse().ok()),
+        height: metadata.get("height").and_then(|h| h.parse().ok()),
+    })
+}
+
+#[tracing::instrument(err, skip(route))]
+async fn purge(req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let token = query_param(&req, "access_token")?.unwrap_or_default();
+    if !is_valid_token(&ctx, &token)? {
+        return errors::invalid_token(req.headers());
+    }
+    let Some(url) = query_param(&req, "url")? else {
+        return errors::missing_param(req.headers(), "url");
+    };
+    let keys = all_r2_keys(&ctx.env, &url);
+    let buckets = ctx.buckets()?;
+    let mut purged = false;
+    for key in keys {
+        for (tier, bucket) in &buckets {
+            if bucket.head(&key).await?.is_some() {
+                bucket.delete(&key).await?;
+                tracing::info!(url = url, key = key, tier = tier, "purged object from R2");
+                purged = true;
+            }
+        }
+        // the edge cache is per data center, so this only clears the local copy
+        Cache::default().delete(edge_cache_key(&key), false).await?;
+        memory::remove(&key);
+    }
+    ctx.bucket("R2_BINDING")?
+        .delete(negative_r2_key(&ctx.env, &url))
+        .await?;
+    if !purged {
+        return errors::not_found(req.headers(), "NOT_CACHED", "object not found");
+    }
+    Response::empty()
+}
+
+#[derive(serde::Serialize)]
+struct KeyInfo {
+    normalized_url: String,
+    key: String,
+    /// Keys of the negotiated format variants, by format.
+    variant_keys: BTreeMap<&'static str, String>,
+    /// Key of the WebP derivative stored when `TRANSCODE_TO` is `webp`.
+    derived_key: String,
+}
+
+/// Reports the R2 keys `url` is stored under, for finding objects in the R2 console.
+#[tracing::instrument(err, skip(route))]
+async fn key_info(req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let token = query_param(&req, "access_token")?.unwrap_or_default();
+    if !is_valid_token(&ctx, &token)? {
+        return errors::invalid_token(req.headers());
+    }
+    let Some(url) = query_param(&req, "url")? else {
+        return errors::missing_param(req.headers(), "url");
+    };
+    Response::from_json(&KeyInfo {
+        normalized_url: normalized_url(&ctx.env, &url),
+        key: get_r2_key(&ctx.env, &url),
+        variant_keys: negotiate::FORMATS
+            .iter()
+            .map(|(format, _)| (*format, format_r2_key(&ctx.env, &url, format)))
+            .collect(),
+        derived_key: derived_r2_key(&ctx.env, &url, "webp"),
+    })
+}
+
+#[derive(serde::Serialize)]
+struct ObjectInfo {
+    key: String,
+    tier: String,
+    url: Option<String>,
+    content_type: Option<String>,
+    /// Stored bytes, which for gzipped objects is less than the served size.
+    size: u64,
+    stored_at: u64,
+    expires_at: Option<u64>,
+    /// Seconds until `expires_at`, `0` once expired.
+    ttl_seconds: Option<u64>,
+    etag: String,
+    pinned: bool,
+    last_accessed: u64,
+    tag: Option<String>,
+    /// The `Content-Encoding` the origin sent, decoded before storing.
+    origin_content_encoding: Option<String>,
+}
+
+/// Describes the stored object `url` would be served from, without its body.
+#[tracing::instrument(err, skip(route))]
+async fn info(req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let token = query_param(&req, "access_token")?.unwrap_or_default();
+    if !is_valid_token(&ctx, &token)? {
+        return errors::invalid_token(req.headers());
+    }
+    let Some(url) = query_param(&req, "url")? else {
+        return errors::missing_param(req.headers(), "url");
+    };
+    let Some(obj) = head_from_r2(&ctx, &url, req.headers()).await? else {
+        return errors::not_found(req.headers(), "NOT_CACHED", "object not found");
+    };
+    let mut metadata = obj.custom_metadata()?;
+    let expires_at = expires_at(&obj)?;
+    Response::from_json(&ObjectInfo {
+        key: obj.key(),
+        tier: metadata
+            .remove("tier")
+            .unwrap_or_else(|| HOT_TIER.to_string()),
+        url: metadata.remove("url"),
+        content_type: metadata.remove("content_type"),
+        size: obj.size(),
+        stored_at: stored_at(&obj)?,
+        expires_at,
+        ttl_seconds: expires_at.map(|expires_at| expires_at.saturating_sub(now_secs())),
+        etag: object_etag(&obj)?,
+        pinned: metadata.get(gc::PINNED_FIELD).map(String::as_str) == Some("true"),
+        last_accessed: gc::last_accessed(&obj)?,
+        tag: metadata.remove(TAG_FIELD),
+        origin_content_encoding: metadata.remove("origin_content_encoding"),
+    })
+}
+
+/// Default and maximum objects scanned by one `GET /list` call.
+const DEFAULT_LIST_LIMIT: u32 = 100;
+const MAX_LIST_LIMIT: u32 = 1000;
+
+#[derive(serde::Serialize)]
+struct ListedObject {
+    key: String,
+    url: String,
+    size: u64,
+    stored_at: u64,
+    tag: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct ListResult {
+    objects: Vec<ListedObject>,
+    /// Where the next call should resume; absent once the whole bucket has been scanned.
+    cursor: Option<String>,
+}
+
+/// Lists cached objects whose origin URL is on `host` (`*.example.com` for subdomains),
+/// whose `X-Cache-Tag` was `tag`, or both.
+/// Keys are hashes, so each call scans `limit` objects of the bucket and filters them
+/// by their stored URL; a page may come back empty while a `cursor` remains.
+#[tracing::instrument(err, skip(route))]
+async fn list(req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let token = query_param(&req, "access_token")?.unwrap_or_default();
+    if !is_valid_token(&ctx, &token)? {
+        return errors::invalid_token(req.headers());
+    }
+    let host = query_param(&req, "host")?;
+    let tag = query_param(&req, "tag")?;
+    if host.is_none() && tag.is_none() {
+        return errors::missing_param(req.headers(), "host or tag");
+    }
+    let limit = match query_param(&req, "limit")? {
+        Some(limit) => match limit.parse::<u32>() {
+            Ok(limit) if limit > 0 => limit.min(MAX_LIST_LIMIT),
+            _ => {
+                return errors::bad_request(
+                    req.headers(),
+                    "INVALID_PARAMETER",
+                    "invalid limit parameter",
+                )
+            }
+        },
+        None => DEFAULT_LIST_LIMIT,
+    };
+    let tier = query_param(&req, "tier")?;
+    let bucket = ctx.tier_bucket(tier.as_deref().unwrap_or(HOT_TIER))?;
+    let mut listing = bucket
+        .list()
+        .limit(limit)
+        .include(vec![Include::CustomMetadata]);
+    let mut prefix = key_namespace(&ctx.env)
+        .map(|namespace| format!("{namespace}/"))
+        .unwrap_or_default();
+    // keys of an exact host share a prefix; `*.` patterns span many
+    if let Some(host) = host.as_deref().filter(|host| !host.starts_with("*.")) {
+        if includes_host(&ctx.env) {
+            prefix.push_str(&format!("{}/", host_slug(host)));
+        }
+    }
+    if !prefix.is_empty() {
+        listing = listing.prefix(prefix);
+    }
+    if let Some(cursor) = query_param(&req, "cursor")? {
+        listing = listing.cursor(cursor);
+    }
+    let page = listing.execute().await?;
+    let host = host.map(|host| host.trim_end_matches('.').to_ascii_lowercase());
+    let mut objects = Vec::new();
+    for obj in page.objects() {
+        // negative cache entries are bookkeeping, not cached images
+        if obj.key().ends_with(".negative") {
+            continue;
+        }
+        let mut metadata = obj.custom_metadata()?;
+        let Some(url) = metadata.remove("url") else {
+            continue;
+        };
+        if let Some(host) = &host {
+            if !domains::matches(&url_host(&url).to_ascii_lowercase(), host) {
+                continue;
+            }
+        }
+        let object_tag = metadata.remove(TAG_FIELD);
+        if tag.is_some() && object_tag != tag {
+            continue;
+        }
+        objects.push(ListedObject {
+            key: obj.key(),
+            url,
+            size: obj.size(),
+            stored_at: stored_at(&obj)?,
+            tag: object_tag,
+        });
+    }
+    Response::from_json(&ListResult {
+        objects,
+        cursor: if page.truncated() {
+            page.cursor()
+        } else {
+            None
+        },
+    })
+}
+
+/// List pages `POST /refresh-host` scans per call, to stay within the Worker's limits.
+const MAX_REFRESH_HOST_PAGES: usize = 5;
+
+#[derive(serde::Deserialize)]
+struct RefreshHostRequest {
+    host: String,
+    access_token: String,
+    cursor: Option<String>,
+    tier: Option<String>,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+struct RefreshHostResult {
+    scanned: usize,
+    refreshed: usize,
+    failed: usize,
+    /// Where the next call should resume; absent once the whole bucket has been scanned.
+    cursor: Option<String>,
+}
+
+/// Refetches `url` from the origin and reports whether R2 now holds a copy stored by
+/// this call, rather than the old one served because the origin failed.
+async fn refresh_url(ctx: &Shared, url: &str, headers: &Headers) -> Result<bool> {
+    if let Some(reason) = blocked_reason(ctx, url) {
+        return Err(Error::from(reason));
+    }
+    let started = now_secs();
+    let opts = RequestOptions {
+        refresh: true,
+        await_put: true,
+        ..Default::default()
+    };
+    cache_url(ctx, url, headers, &opts).await?;
+    match head_from_r2(ctx, url, headers).await? {
+        Some(obj) => Ok(stored_at(&obj)? >= started),
+        None => Ok(false),
+    }
+}
+
+/// Refetches every cached object whose origin URL is on `host`, e.g. after the site
+/// moved to another CDN. Matches are found by scanning the bucket, at most
+/// `MAX_BATCH_URLS` per call; each call returns a `cursor` to pass to the next until
+/// none is left.
+#[tracing::instrument(err, skip(route))]
+async fn refresh_host(mut req: Request, route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let ctx = Shared::from(route);
+    let body: RefreshHostRequest = match read_json_body(&ctx, &mut req).await? {
+        Ok(body) => body,
+        Err(res) => return Ok(res),
+    };
+    if !is_valid_token(&ctx, &body.access_token)? {
+        return errors::invalid_token(req.headers());
+    }
+    let host = body.host.trim_end_matches('.').to_ascii_lowercase();
+    let max_urls: usize = var_parse(&ctx.env, "MAX_BATCH_URLS")?.unwrap_or(DEFAULT_MAX_BATCH_URLS);
+    let bucket = ctx.tier_bucket(body.tier.as_deref().unwrap_or(HOT_TIER))?;
+    let mut prefix = key_namespace(&ctx.env)
+        .map(|namespace| format!("{namespace}/"))
+        .unwrap_or_default();
+    if includes_host(&ctx.env) && !host.starts_with("*.") {
+        prefix.push_str(&format!("{}/", host_slug(&host)));
+    }
+    let mut summary = RefreshHostResult::default();
+    let mut urls: Vec<String> = Vec::new();
+    let mut cursor = body.cursor;
+    for _ in 0..MAX_REFRESH_HOST_PAGES {
+        // a page never holds more matches than are left to refresh, so none are skipped
+        let remaining = max_urls.saturating_sub(urls.len());
+        if remaining == 0 {
+            break;
+        }
+        let mut listing = bucket
+            .list()
+            .limit(u32::try_from(remaining.min(1000)).unwrap_or(1000))
+            .include(vec![Include::CustomMetadata]);
+        if !prefix.is_empty() {
+            listing = listing.prefix(prefix.clone());
+        }
+        if let Some(cursor) = cursor.take() {
+            listing = listing.cursor(cursor);
+        }
+        let page = listing.execute().await?;
+        let objects = page.objects();
+        summary.scanned += objects.len();
+        for obj in objects {
+            if obj.key().ends_with(".negative") {
+                continue;
+            }
+            let Some(url) = obj.custom_metadata()?.remove("url") else {
+                continue;
+            };
+            // format variants share a URL and are refreshed together
+            if domains::matches(&url_host(&url).to_ascii_lowercase(), &host) && !urls.contains(&url)
+            {
+                urls.push(url);
+            }
+        }
+        if !page.truncated() {
+            break;
+        }
+        cursor = page.cursor();
+    }
+    summary.cursor = cursor;
+    let headers = req.headers();
+    let results: Vec<Result<bool>> = futures::stream::iter(&urls)
+        .map(|url| refresh_url(&ctx, url, headers))
+        .buffered(BATCH_CONCURRENCY)
+        .collect()
+        .await;
+    for (url, result) in urls.iter().zip(results) {
+        match result {
+            Ok(true) => summary.refreshed += 1,
+            Ok(false) => {
+                tracing::warn!(url = url, "origin did not provide a fresh copy");
+                summary.failed += 1;
+            }
+            Err(e) => {
+                tracing::warn!(url = url, error = %e, "refresh failed");
+                summary.failed += 1;
+            }
+        }
+    }
+    tracing::info!(host = host, summary = ?summary, "host refresh finished");
+    Response::from_json(&summary)
+}
+
+/// The methods registered for each path in [`fetch`], advertised in `Allow`.
+const ROUTE_METHODS: &[(&str, &str)] = &[
+    ("/", "GET, HEAD, POST, DELETE, OPTIONS"),
+    ("/batch", "POST, OPTIONS"),
+    ("/gc", "POST, OPTIONS"),
+    ("/health", "GET, OPTIONS"),
+    ("/info", "GET, OPTIONS"),
+    ("/key", "GET, OPTIONS"),
+    ("/list", "GET, OPTIONS"),
+    ("/metrics", "GET, OPTIONS"),
+    ("/pin", "POST, OPTIONS"),
+    ("/refresh-host", "POST, OPTIONS"),
+    ("/placeholder", "GET, OPTIONS"),
+    ("/selftest", "GET, OPTIONS"),
+    ("/stats", "GET, OPTIONS"),
+    ("/unpin", "POST, OPTIONS"),
+    ("/warm-feed", "POST, OPTIONS"),
+];
+
+/// Answers `OPTIONS` with the path's `Allow` header. This doubles as the CORS preflight
+/// response; the CORS headers themselves are added in [`fetch`].
+async fn options(req: Request, _route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let path = req.path();
+    let res = Response::empty()?.with_status(204);
+    if let Some((_, methods)) = ROUTE_METHODS.iter().find(|(route, _)| *route == path) {
+        res.headers().set("Allow", methods)?;
+    }
+    Ok(res)
+}
+
+#[derive(serde::Serialize)]
+struct RouteError<'a> {
+    error: &'static str,
+    code: &'static str,
+    method: &'a str,
+    path: &'a str,
+}
+
+/// Answers paths no route is registered for.
+async fn not_found(req: Request, _route: RouteContext<Rc<Context>>) -> Result<Response> {
+    let path = req.path();
+    let method = req.method().to_string();
+    tracing::warn!(method = method, path = path, "request for unknown route");
+    Ok(Response::from_json(&RouteError {
+        error: "not found",
+        code: "NOT_FOUND",
+        method: &method,
+        path: &path,
+    })?
+    .with_status(404))
+}
+
+/// Replaces the router's bare `405` for a known path with one naming the allowed methods.
+fn method_not_allowed(method: &str, path: &str) -> Result<Response> {
+    tracing::warn!(
+        method = method,
+        path = path,
+        "request with unsupported method"
+    );
+    let res = Response::from_json(&RouteError {
+        error: "method not allowed",
+        code: "METHOD_NOT_ALLOWED",
+        method,
+        path,
+    })?
+    .with_status(405);
+    if let Some((_, methods)) = ROUTE_METHODS.iter().find(|(route, _)| *route == path) {
+        res.headers().set("Allow", methods)?;
+    }
+    Ok(res)
+}
+
+/// Installs the tracing subscriber on the first request, as `start` runs before the
+/// environment is available. Logs go to the console as JSON unless `LOG_FORMAT=pretty`,
+/// at `LOG_LEVEL` (default `info`).
+fn init_tracing(env: &Env) {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let format = env.var("LOG_FORMAT").map(|v| v.to_string()).ok();
+        let pretty = format.as_deref() == Some("pretty");
+        let level = env.var("LOG_LEVEL").map(|v| v.to_string()).ok();
+        let level_filter = level
+            .as_deref()
+            .and_then(|level| LevelFilter::from_str(level).ok())
+            .unwrap_or(LevelFilter::INFO);
+        let fmt_layer = tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_timer(UtcTime::rfc_3339())
+            .with_writer(MakeWebConsoleWriter::default());
+        let fmt_layer = if pretty {
+            fmt_layer.pretty().with_filter(sampling::filter()).boxed()
+        } else {
+            fmt_layer.json().with_filter(sampling::filter()).boxed()
+        };
+        let perf_layer = performance_layer().with_details_from_fields(Pretty::default());
+        tracing_subscriber::registry()
+            .with(level_filter)
+            .with(sampling::SamplingLayer)
+            .with(fmt_layer)
+            .with(perf_layer)
+            .init();
+        if let Some(format) = format.as_deref() {
+            if !matches!(format, "json" | "pretty") {
+                tracing::warn!(format = format, "unknown LOG_FORMAT, using json");
+            }
+        }
+        if let Some(level) = level.as_deref() {
+            if LevelFilter::from_str(level).is_err() {
+                tracing::warn!(level = level, "unknown LOG_LEVEL, using info");
+            }
+        }
+    });
+}
+
+#[event(fetch)]
+async fn fetch(req: Request, env: Env, ctx: Context) -> Result<Response> {
+    init_tracing(&env);
+    let origin = req.headers().get("Origin")?;
+    let allowed_origins = var_list(&env, "ALLOWED_ORIGINS", "");
+    // decided once so that a request's info logs are either all kept or all dropped
+    let sample_rate: f64 = var_parse(&env, "LOG_SAMPLE_RATE")?.unwrap_or(1.0);
+    let sampled = worker::js_sys::Math::random() < sample_rate;
+    let span = tracing::info_span!("request", sampled);
+    let router = Router::with_data(Rc::new(ctx))
+        .get_async("/", get)
+        .get_async("/stats", get_stats)
+        .get_async("/health", health)
+        .get_async("/placeholder", placeholder)
+        .get_async("/key", key_info)
+        .get_async("/info", info)
+        .get_async("/list", list)
+        .get_async("/selftest", selftest)
+        .get_async("/metrics", metrics)
+        .head_async("/", head)
+        .post_async("/", post)
+        .post_async("/batch", batch)
+        .post_async("/warm-feed", warm_feed)
+        .post_async("/gc", gc)
+        .post_async("/refresh-host", refresh_host)
+        .post_async("/pin", pin)
+        .post_async("/unpin", unpin)
+        .delete_async("/", purge);
+    // every path answers OPTIONS, with or without CORS
+    let router = ROUTE_METHODS
+        .iter()
+        .fold(router, |router, (path, _)| {
+            router.options_async(path, options)
+        })
+        .or_else_any_method_async("/*path", not_found);
+    let method = req.method().to_string();
+    let path = req.path();
+    let req_headers = req.headers().clone();
+    let res = match router.run(req, env).instrument(span.clone()).await {
+        Ok(res) => res,
+        // the handler has logged the error already; its message stays out of the response
+        Err(_) => errors::respond(&req_headers, 500, "INTERNAL", "internal error")?,
+    };
+    let res = if res.status_code() == 405 {
+        span.in_scope(|| method_not_allowed(&method, &path))?
+    } else {
+        res
+    };
+    let Some(origin) = origin else {
+        return Ok(res);
+    };
+    // responses passed through from the origin carry immutable headers
+    let headers = res.headers().clone();
+    cors::apply(&headers, &origin, &allowed_origins)?;
+    Ok(res.with_headers(headers))
+}
+
+/// Consumes `CACHE_QUEUE`, performing the R2 writes [`put_in_r2_in_background`] enqueued.
+/// Failed writes are retried by the queue.
+#[event(queue)]
+async fn queue(batch: MessageBatch<queue::CacheWrite>, env: Env, ctx: Context) -> Result<()> {
+    init_tracing(&env);
+    let ctx = Shared {
+        env,
+        data: Rc::new(ctx),
+    };
+    for message in batch.messages()? {
+        let write = message.body();
+        let result = async {
+            put_in_r2(
+                &ctx,
+                &write.url,
+                write.format.as_deref(),
+                &write.headers()?,
+                write.body()?,
+                &write.options(),
+            )
+            .await
+        };
+        match result.await {
+            Ok(()) => message.ack(),
+            Err(e) => {
+                tracing::warn!(url = write.url, error = %e, "queued R2 write failed, retrying");
+                message.retry();
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_bodies() {
+        assert_eq!(oversized(100, false, 100, None), None);
+        assert_eq!(oversized(101, false, 100, None), Some(Oversized::TooLarge));
+        // a compressed body past the limit inflated there, with or without Content-Length
+        assert_eq!(oversized(101, true, 100, None), Some(Oversized::Inflated));
+        assert_eq!(
+            oversized(51, true, 100, Some(50)),
+            Some(Oversized::Inflated)
+        );
+        assert_eq!(oversized(50, true, 100, Some(50)), None);
+    }
+
+    /// `STRIP_QUERY_PARAMS` when it is unset.
+    fn default_strip_params() -> Vec<String> {
+        normalize::DEFAULT_STRIP_QUERY_PARAMS
+            .split(',')
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn shard_levels_are_parsed() {
+        assert_eq!(parse_shard_levels("2,2"), [2, 2]);
+        assert_eq!(parse_shard_levels(" 3 "), [3]);
+        assert_eq!(parse_shard_levels("1, 2, 3"), [1, 2, 3]);
+        assert_eq!(parse_shard_levels("0"), [] as [usize; 0]);
+        assert_eq!(parse_shard_levels(""), [] as [usize; 0]);
+        assert_eq!(parse_shard_levels("2,0,2"), [2, 2]);
+    }
+
+    #[test]
+    fn invalid_shard_levels_fall_back_to_the_default() {
+        for levels in ["two", "2,-1", "8,8,1", "17"] {
+            assert_eq!(
+                parse_shard_levels(levels),
+                DEFAULT_KEY_SHARD_LEVELS,
+                "{levels}"
+            );
+        }
+        assert_eq!(parse_shard_levels("8,8"), [8, 8]);
+    }
+
+    #[test]
+    fn keys_are_sharded_by_level() {
+        let hex = "0123456789abcdef0123456789abcdef";
+        assert_eq!(sharded(hex, &[2, 2]), "01/23/456789abcdef0123456789abcdef");
+        assert_eq!(sharded(hex, &[3]), "012/3456789abcdef0123456789abcdef");
+        assert_eq!(
+            sharded(hex, &[1, 1, 1]),
+            "0/1/2/3456789abcdef0123456789abcdef"
+        );
+        assert_eq!(sharded(hex, &[]), hex);
+        // the deepest sharding allowed still leaves the shortest hash a file name
+        assert_eq!(sharded(hex, &[8, 8]), "01234567/89abcdef/0123456789abcdef",);
+    }
+
+    /// Origin response headers by name, as the `Headers` getter would return them.
+    fn origin_headers<'a>(
+        headers: &'a [(&str, &str)],
+    ) -> impl Fn(&str) -> Result<Option<String>> + 'a {
+        |name| {
+            Ok(headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.to_string()))
+        }
+    }
+
+    #[test]
+    fn content_disposition_round_trips() {
+        let disposition = "attachment; filename=\"chart 2024.png\"";
+        let stored: HashMap<_, _> = header_metadata(origin_headers(&[
+            ("Content-Disposition", disposition),
+            ("Set-Cookie", "session=1"),
+        ]))
+        .unwrap()
+        .into_iter()
+        .collect();
+        assert_eq!(
+            stored.get("content_disposition").map(String::as_str),
+            Some(disposition),
+        );
+        let replayed: Vec<_> = replayed_headers(&stored).collect();
+        assert_eq!(
+            replayed,
+            [("Content-Disposition", &disposition.to_string())],
+        );
+    }
+
+    #[test]
+    fn replayed_headers_cannot_inject_lines() {
+        let stored: HashMap<_, _> = header_metadata(origin_headers(&[(
+            "Content-Disposition",
+            "attachment; filename=a.png\r\nSet-Cookie: session=1",
+        )]))
+        .unwrap()
+        .into_iter()
+        .collect();
+        assert_eq!(
+            stored["content_disposition"],
+            "attachment; filename=a.pngSet-Cookie: session=1",
+        );
+    }
+
+    #[test]
+    fn jitter_stays_within_percent() {
+        // `random` runs from 0 for the largest cut to just below 1 for the largest extension
+        for (percent, low, high) in [(0.0, 3600, 3600), (10.0, 3240, 3960), (100.0, 0, 7200)] {
+            assert_eq!(jitter(3600, percent, 0.0), low, "{percent}%");
+            assert_eq!(jitter(3600, percent, 1.0), high, "{percent}%");
+            assert_eq!(jitter(3600, percent, 0.5), 3600, "{percent}%");
+            for random in [0.1, 0.25, 0.75, 0.999_999] {
+                let ttl = jitter(3600, percent, random);
+                assert!(
+                    (low..=high).contains(&ttl),
+                    "{ttl} for {percent}% at {random}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn not_modified_serves_the_revalidated_copy() {
+        assert_eq!(redirection_outcome(304, true), Redirection::Revalidated);
+    }
+
+    #[test]
+    fn other_redirections_fall_back() {
+        assert_eq!(redirection_outcome(304, false), Redirection::Fallback);
+        for status in [300, 301, 302, 307, 308] {
+            assert_eq!(
+                redirection_outcome(status, true),
+                Redirection::Fallback,
+                "{status}"
+            );
+            assert_eq!(
+                redirection_outcome(status, false),
+                Redirection::Fallback,
+                "{status}"
+            );
+        }
+    }
+
+    #[test]
+    fn head_length_matches_the_get() {
+        let plain = HashMap::from([("content_length".to_string(), "5000".to_string())]);
+        assert_eq!(
+            served_encoding(&plain, 5000, true),
+            ("5000".to_string(), None)
+        );
+        assert_eq!(
+            served_encoding(&plain, 5000, false),
+            ("5000".to_string(), None)
+        );
+        let gzipped = HashMap::from([
+            ("content_length".to_string(), "5000".to_string()),
+            ("content_encoding".to_string(), "gzip".to_string()),
+        ]);
+        assert_eq!(
+            served_encoding(&gzipped, 1200, true),
+            ("1200".to_string(), Some("gzip")),
+        );
+        assert_eq!(
+            served_encoding(&gzipped, 1200, false),
+            ("5000".to_string(), None)
+        );
+    }
+
+    /// The sharded hash of a normalized URL must never change, or every stored object
+    /// would be orphaned.
+    #[test]
+    fn keys_are_stable_per_algorithm() {
+        let url = normalize::normalize_url(
+            "https://Example.com/img/a.png?utm_source=x&b=1#top",
+            &default_strip_params(),
+        );
+        assert_eq!(url, "https://example.com/img/a.png?b=1");
+        let key = |algorithm| sharded(&hash_hex(algorithm, url.as_bytes()), &[2, 2]);
+        let sha256 = "92/b4/8ea54a65ded1beba70d8764af3c4d23200f51419a9a03daebfa2ee374eef";
+        assert_eq!(key(None), sha256);
+        assert_eq!(key(Some("sha256")), sha256);
+        assert_eq!(key(Some("unknown")), sha256);
+        assert_eq!(
+            key(Some("blake3")),
+            "b7/f3/75b6064401df9098895589767adeed9c030d27845a3f4a170e0fed19e81d",
+        );
+        assert_eq!(key(Some("xxh3")), "74/8b/6813ed70a36fe075321a55051847");
+    }
+
+    fn unset() -> Result<String> {
+        Err(Error::from("FIXED_REFERER is not set"))
+    }
+
+    #[test]
+    fn origin_referer_is_the_url_origin() {
+        let referer = referer_for("origin", "https://img.example.com:8443/a/b.png?x=1", unset);
+        assert_eq!(
+            referer.unwrap().as_deref(),
+            Some("https://img.example.com:8443/"),
+        );
+        assert_eq!(referer_for("origin", "not a url", unset).unwrap(), None);
+    }
+
+    #[test]
+    fn none_sends_no_referer() {
+        assert_eq!(
+            referer_for("none", "https://example.com/a.png", unset).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn fixed_referer_is_read_only_when_used() {
+        let fixed = || Ok("https://reader.example.org/".to_string());
+        assert_eq!(
+            referer_for("fixed", "https://example.com/a.png", fixed)
+                .unwrap()
+                .as_deref(),
+            Some("https://reader.example.org/"),
+        );
+        assert!(referer_for("fixed", "https://example.com/a.png", unset).is_err());
+    }
+
+    #[test]
+    fn unknown_strategy_is_an_error() {
+        assert!(referer_for("same-origin", "https://example.com/a.png", unset).is_err());
+    }
+
+    #[test]
+    fn large_identity_bodies_are_streamed() {
+        let mib = 1024 * 1024;
+        assert_eq!(
+            stream_length(Some("8388608"), None, 4 * mib, None),
+            Some(8 * mib)
+        );
+        assert_eq!(
+            stream_length(Some("8388608"), Some("identity"), 4 * mib, Some(8 * mib)),
+            Some(8 * mib),
+        );
+        assert_eq!(
+            stream_length(Some("4194304"), None, 4 * mib, None),
+            Some(4 * mib)
+        );
+    }
+
+    #[test]
+    fn other_bodies_are_buffered() {
+        let mib = 1024 * 1024;
+        // below the threshold
+        assert_eq!(stream_length(Some("1024"), None, 4 * mib, None), None);
+        // the length is unknown
+        assert_eq!(stream_length(None, None, 4 * mib, None), None);
+        assert_eq!(stream_length(Some("lots"), None, 4 * mib, None), None);
+        // decoded by the runtime, so the length is of the encoded bytes
+        assert_eq!(
+            stream_length(Some("8388608"), Some("gzip"), 4 * mib, None),
+            None
+        );
+        // left to the buffered path to reject
+        assert_eq!(
+            stream_length(Some("8388608"), None, 4 * mib, Some(mib)),
+            None
+        );
+    }
 }
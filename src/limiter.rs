@@ -0,0 +1,57 @@
+use std::{cell::RefCell, collections::VecDeque};
+
+use futures::channel::oneshot;
+
+/// Caps how many origin fetches this isolate runs at once.
+///
+/// Workers run an isolate's requests on a single thread, so the state is a plain
+/// thread-local rather than an atomic. The cap is therefore per isolate, not global.
+#[derive(Default)]
+struct Limiter {
+    in_flight: usize,
+    waiters: VecDeque<oneshot::Sender<()>>,
+}
+
+thread_local! {
+    static LIMITER: RefCell<Limiter> = RefCell::default();
+}
+
+/// A slot in the limiter, released when dropped.
+pub(crate) struct Permit(());
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        LIMITER.with_borrow_mut(|limiter| {
+            // hand the slot straight to the next live waiter, if any
+            while let Some(waiter) = limiter.waiters.pop_front() {
+                if waiter.send(()).is_ok() {
+                    return;
+                }
+            }
+            limiter.in_flight -= 1;
+        });
+    }
+}
+
+/// Waits until fewer than `max` fetches are in flight.
+pub(crate) async fn acquire(max: usize) -> Permit {
+    let rx = LIMITER.with_borrow_mut(|limiter| {
+        if limiter.in_flight < max.max(1) {
+            limiter.in_flight += 1;
+            return None;
+        }
+        let (tx, rx) = oneshot::channel();
+        limiter.waiters.push_back(tx);
+        Some(rx)
+    });
+    if let Some(rx) = rx {
+        // the sender is only dropped after transferring its slot to us
+        let _ = rx.await;
+    }
+    Permit(())
+}
+
+/// The number of origin fetches currently holding a permit.
+pub(crate) fn in_flight() -> usize {
+    LIMITER.with_borrow(|limiter| limiter.in_flight)
+}
@@ -0,0 +1,269 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use time::{macros::format_description, OffsetDateTime};
+
+/// Bodies larger than this are never held in memory, whatever the budget; the cache is
+/// meant for favicons and default avatars, not photos.
+pub(crate) const MAX_OBJECT_BYTES: usize = 32 * 1024;
+
+/// Longest an entry is served from memory. Purges only reach the isolate that handles
+/// them, so other isolates may serve a purged object for up to this long.
+pub(crate) const MAX_ENTRY_AGE_SECS: u64 = 60;
+
+/// Response headers as name/value pairs, as `Headers` cannot outlive a request.
+pub(crate) type HeaderList = Vec<(String, String)>;
+
+/// How much one isolate may hold, from `MEMORY_CACHE_MAX_ITEMS` and
+/// `MEMORY_CACHE_MAX_BYTES`.
+pub(crate) struct Limits {
+    pub(crate) max_items: usize,
+    pub(crate) max_bytes: usize,
+}
+
+struct Entry {
+    headers: HeaderList,
+    body: Vec<u8>,
+    stored_at: u64,
+    expires_at: u64,
+    /// Value of [`Lru::tick`] when the entry was last read or written.
+    last_used: u64,
+}
+
+/// Least recently used cache of response bodies, keyed by R2 key.
+///
+/// Like the fetch limiter this is per isolate and lives in a thread-local. Entries are few
+/// by design, so eviction simply scans for the oldest.
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, Entry>,
+    bytes: usize,
+    tick: u64,
+}
+
+impl Lru {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.body.len();
+        }
+    }
+
+    fn evict_until_within(&mut self, limits: &Limits) {
+        while self.entries.len() > limits.max_items || self.bytes > limits.max_bytes {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                return;
+            };
+            self.remove(&oldest);
+        }
+    }
+}
+
+thread_local! {
+    static CACHE: RefCell<Lru> = RefCell::default();
+}
+
+/// The headers and body cached for `key`, unless missing or expired. The headers are
+/// [aged](aged) by the time spent in memory.
+pub(crate) fn get(key: &str, now: u64) -> Option<(HeaderList, Vec<u8>)> {
+    CACHE.with_borrow_mut(|cache| {
+        cache.tick += 1;
+        let tick = cache.tick;
+        let entry = cache.entries.get_mut(key)?;
+        if entry.expires_at <= now {
+            cache.remove(key);
+            return None;
+        }
+        entry.last_used = tick;
+        let headers = aged(&entry.headers, now.saturating_sub(entry.stored_at), now);
+        Some((headers, entry.body.clone()))
+    })
+}
+
+/// Caches a response for `ttl` seconds, at most [`MAX_ENTRY_AGE_SECS`], evicting the
+/// least recently used entries to stay within `limits`. Bodies over
+/// [`MAX_OBJECT_BYTES`] or the whole byte budget are ignored, as are encoded responses:
+/// the key does not vary on `Accept-Encoding`, so they could reach clients that cannot
+/// decode them.
+pub(crate) fn insert(
+    key: String,
+    headers: HeaderList,
+    body: Vec<u8>,
+    ttl: u64,
+    now: u64,
+    limits: &Limits,
+) {
+    let ttl = ttl.min(MAX_ENTRY_AGE_SECS);
+    if ttl == 0 || limits.max_items == 0 || body.len() > MAX_OBJECT_BYTES.min(limits.max_bytes) {
+        return;
+    }
+    let encoded = headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Content-Encoding"));
+    if encoded {
+        return;
+    }
+    CACHE.with_borrow_mut(|cache| {
+        cache.tick += 1;
+        cache.remove(&key);
+        cache.bytes += body.len();
+        let entry = Entry {
+            headers,
+            body,
+            stored_at: now,
+            expires_at: now + ttl,
+            last_used: cache.tick,
+        };
+        cache.entries.insert(key, entry);
+        cache.evict_until_within(limits);
+    });
+}
+
+/// `headers` as they would have been sent `elapsed` seconds after they were stored:
+/// `Age` grows, `max-age` shrinks and `Date` becomes `now`.
+fn aged(headers: &HeaderList, elapsed: u64, now: u64) -> HeaderList {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = match name.to_ascii_lowercase().as_str() {
+                "age" => value
+                    .trim()
+                    .parse::<u64>()
+                    .map_or_else(|_| value.clone(), |age| (age + elapsed).to_string()),
+                "cache-control" => value
+                    .split(',')
+                    .map(|directive| {
+                        let directive = directive.trim();
+                        match directive
+                            .strip_prefix("max-age=")
+                            .and_then(|max_age| max_age.parse::<u64>().ok())
+                        {
+                            Some(max_age) => format!("max-age={}", max_age.saturating_sub(elapsed)),
+                            None => directive.to_string(),
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                "date" => http_date(now).unwrap_or_else(|| value.clone()),
+                _ => value.clone(),
+            };
+            (name.clone(), value)
+        })
+        .collect()
+}
+
+/// Formats a unix time as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(secs: u64) -> Option<String> {
+    let time = OffsetDateTime::from_unix_timestamp(i64::try_from(secs).ok()?).ok()?;
+    let format = format_description!(
+        "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+    );
+    time.format(format).ok()
+}
+
+/// Drops `key` from this isolate's cache.
+pub(crate) fn remove(key: &str) {
+    CACHE.with_borrow_mut(|cache| cache.remove(key));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        max_items: 2,
+        max_bytes: 100,
+    };
+
+    fn headers() -> HeaderList {
+        vec![("content-type".to_string(), "image/png".to_string())]
+    }
+
+    fn insert_body(key: &str, len: usize, now: u64, limits: &Limits) {
+        insert(key.to_string(), headers(), vec![0; len], 30, now, limits);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_entry() {
+        insert_body("a", 10, 0, &LIMITS);
+        insert_body("b", 10, 0, &LIMITS);
+        // reading `a` makes `b` the oldest
+        assert!(get("a", 1).is_some());
+        insert_body("c", 10, 1, &LIMITS);
+        assert!(get("a", 2).is_some());
+        assert!(get("b", 2).is_none());
+        assert!(get("c", 2).is_some());
+    }
+
+    #[test]
+    fn keeps_within_the_byte_budget() {
+        insert_body("a", 60, 0, &LIMITS);
+        insert_body("b", 60, 0, &LIMITS);
+        assert!(get("a", 1).is_none());
+        assert!(get("b", 1).is_some());
+        // larger than the whole budget, so never cached
+        insert_body("c", 101, 1, &LIMITS);
+        assert!(get("c", 1).is_none());
+        assert!(get("b", 1).is_some());
+    }
+
+    #[test]
+    fn ignores_bodies_over_the_object_cap() {
+        let limits = Limits {
+            max_items: 10,
+            max_bytes: 10 * MAX_OBJECT_BYTES,
+        };
+        insert_body("big", MAX_OBJECT_BYTES + 1, 0, &limits);
+        assert!(get("big", 0).is_none());
+        insert_body("small", MAX_OBJECT_BYTES, 0, &limits);
+        assert!(get("small", 0).is_some());
+    }
+
+    #[test]
+    fn ignores_encoded_responses() {
+        let headers = vec![("content-encoding".to_string(), "gzip".to_string())];
+        insert("a".to_string(), headers, vec![0; 10], 30, 0, &LIMITS);
+        assert!(get("a", 0).is_none());
+    }
+
+    #[test]
+    fn entries_expire_after_at_most_the_max_age() {
+        insert("a".to_string(), headers(), vec![0; 10], 3600, 0, &LIMITS);
+        assert!(get("a", MAX_ENTRY_AGE_SECS - 1).is_some());
+        assert!(get("a", MAX_ENTRY_AGE_SECS).is_none());
+    }
+
+    #[test]
+    fn replayed_headers_are_aged() {
+        let headers = vec![
+            ("age".to_string(), "5".to_string()),
+            (
+                "cache-control".to_string(),
+                "public, max-age=100".to_string(),
+            ),
+            (
+                "date".to_string(),
+                "Thu, 01 Jan 1970 00:00:00 GMT".to_string(),
+            ),
+        ];
+        insert("a".to_string(), headers, vec![0; 10], 60, 0, &LIMITS);
+        let (headers, _) = get("a", 30).unwrap();
+        assert_eq!(
+            headers,
+            vec![
+                ("age".to_string(), "35".to_string()),
+                (
+                    "cache-control".to_string(),
+                    "public, max-age=70".to_string()
+                ),
+                (
+                    "date".to_string(),
+                    "Thu, 01 Jan 1970 00:00:30 GMT".to_string()
+                ),
+            ]
+        );
+    }
+}
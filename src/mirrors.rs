@@ -0,0 +1,34 @@
+use std::collections::BTreeMap;
+
+use worker::Url;
+
+use crate::domains;
+
+/// The URLs to try when the origin of `url` fails, from `MIRROR_HOSTS`: host patterns
+/// (`example.com` or `*.example.com`) mapped to alternate hosts, in which `{host}` stands
+/// for the original host. Only the host is replaced; scheme, path and query are kept.
+pub(crate) fn mirror_urls(url: &Url, mirrors: &BTreeMap<String, Vec<String>>) -> Vec<String> {
+    let Some(host) = url.host_str() else {
+        return Vec::new();
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let mut urls = Vec::new();
+    for (pattern, templates) in mirrors {
+        if !domains::matches(&host, pattern) {
+            continue;
+        }
+        for template in templates {
+            let mut mirror = url.clone();
+            if mirror
+                .set_host(Some(&template.replace("{host}", &host)))
+                .is_ok()
+            {
+                let mirror = mirror.to_string();
+                if !urls.contains(&mirror) {
+                    urls.push(mirror);
+                }
+            }
+        }
+    }
+    urls
+}
@@ -0,0 +1,56 @@
+/// Formats that get their own R2 object, most efficient first.
+pub(crate) const FORMATS: &[(&str, &str)] = &[("avif", "image/avif"), ("webp", "image/webp")];
+
+/// Picks the most efficient format the client advertises in `Accept`.
+///
+/// Returns `None` when the client accepts none of [`FORMATS`], in which case the object is
+/// keyed exactly as before negotiation existed. Wildcards are ignored, since `image/*`
+/// says nothing about which modern formats a client can decode.
+pub(crate) fn format_bucket(accept: Option<&str>) -> Option<&'static str> {
+    let accepted = accepted_types(accept?);
+    FORMATS
+        .iter()
+        .find(|(_, media_type)| {
+            accepted
+                .iter()
+                .any(|accepted| accepted.eq_ignore_ascii_case(media_type))
+        })
+        .map(|(format, _)| *format)
+}
+
+/// Whether `accept` explicitly lists `media_type`, ignoring wildcards as
+/// [`format_bucket`] does.
+pub(crate) fn accepts(accept: Option<&str>, media_type: &str) -> bool {
+    accept.is_some_and(|accept| {
+        accepted_types(accept)
+            .iter()
+            .any(|accepted| accepted.eq_ignore_ascii_case(media_type))
+    })
+}
+
+/// The media types in `accept`, minus those refused with `q=0`.
+fn accepted_types(accept: &str) -> Vec<&str> {
+    accept
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let media_type = parts.next()?;
+            let rejected = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (!rejected).then_some(media_type)
+        })
+        .collect()
+}
+
+/// Whether an origin response's `Vary` header says the representation depends on `Accept`.
+pub(crate) fn varies_on_accept(vary: Option<&str>) -> bool {
+    vary.is_some_and(|vary| {
+        vary.split(',')
+            .map(str::trim)
+            .any(|field| field == "*" || field.eq_ignore_ascii_case("accept"))
+    })
+}
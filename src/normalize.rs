@@ -0,0 +1,139 @@
+use worker::Url;
+
+/// Query parameters stripped before hashing when `STRIP_QUERY_PARAMS` is unset.
+pub(crate) const DEFAULT_STRIP_QUERY_PARAMS: &str = "utm_*,fbclid,gclid";
+
+/// Canonicalizes `url` so equivalent spellings of the same image share one cache key.
+///
+/// Parsing lowercases the scheme and host and drops default ports; on top of that the
+/// fragment is removed, query parameters matching `strip` (a trailing `*` matches by
+/// prefix) are dropped, and the remaining ones are sorted. Unparseable input is returned
+/// unchanged.
+pub(crate) fn normalize_url(url: &str, strip: &[String]) -> String {
+    let Ok(mut parsed) = Url::parse(url) else {
+        return url.to_string();
+    };
+    parsed.set_fragment(None);
+    let mut pairs: Vec<(String, String)> = parsed
+        .query_pairs()
+        .filter(|(k, _)| !strip.iter().any(|pattern| matches(pattern, k)))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    if pairs.is_empty() {
+        parsed.set_query(None);
+    } else {
+        pairs.sort();
+        parsed.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    parsed.to_string()
+}
+
+/// Removes the query parameter `name` from `url`, keeping the rest of it as written.
+pub(crate) fn strip_param(url: &str, name: &str) -> String {
+    let Ok(mut parsed) = Url::parse(url) else {
+        return url.to_string();
+    };
+    if !parsed.query_pairs().any(|(k, _)| k == name) {
+        return url.to_string();
+    }
+    let pairs: Vec<(String, String)> = parsed
+        .query_pairs()
+        .filter(|(k, _)| k != name)
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    if pairs.is_empty() {
+        parsed.set_query(None);
+    } else {
+        parsed.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    parsed.to_string()
+}
+
+/// Wrappings peeled off by [`unwrap`], enough for a proxy URL wrapped in itself a
+/// couple of times.
+const MAX_UNWRAPS: usize = 3;
+
+/// Recovers the image URL from a proxy URL matching one of `prefixes`, such as
+/// FreshRSS's `https://rss.example.com/i/?url=`. A `*` in a prefix matches any text.
+///
+/// What follows the prefix, up to the next `&`, is percent-decoded and used when it is
+/// itself an http(s) URL; anything else is returned unchanged.
+pub(crate) fn unwrap(url: &str, prefixes: &[String]) -> String {
+    let mut url = url.to_string();
+    for _ in 0..MAX_UNWRAPS {
+        let inner = prefixes.iter().find_map(|prefix| {
+            let rest = strip_glob_prefix(&url, prefix)?;
+            let encoded = rest.split('&').next().unwrap_or(rest);
+            // a leading name keeps any `=` in an unencoded URL in the value
+            let query = format!("url={encoded}");
+            let (_, inner) = url::form_urlencoded::parse(query.as_bytes()).next()?;
+            let parsed = Url::parse(&inner).ok()?;
+            matches!(parsed.scheme(), "http" | "https").then(|| inner.into_owned())
+        });
+        match inner {
+            Some(inner) => url = inner,
+            None => break,
+        }
+    }
+    url
+}
+
+/// What follows `prefix` in `s`, each `*` in `prefix` matching as little as possible.
+fn strip_glob_prefix<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    let mut pieces = prefix.split('*');
+    let mut rest = s.strip_prefix(pieces.next()?)?;
+    for piece in pieces {
+        let start = rest.find(piece)?;
+        rest = &rest[start + piece.len()..];
+    }
+    Some(rest)
+}
+
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip() -> Vec<String> {
+        DEFAULT_STRIP_QUERY_PARAMS
+            .split(',')
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn equivalent_urls_normalize_alike() {
+        let normalized = "https://example.com/a.png?a=2&b=1";
+        for url in [
+            "https://example.com/a.png?b=1&a=2",
+            "HTTPS://EXAMPLE.com:443/a.png?a=2&b=1",
+            "https://example.com/a.png?a=2&b=1#fragment",
+            "https://example.com/a.png?utm_source=feed&a=2&fbclid=x&b=1",
+        ] {
+            assert_eq!(normalize_url(url, &strip()), normalized, "{url}");
+        }
+    }
+
+    #[test]
+    fn fully_stripped_queries_are_removed() {
+        assert_eq!(
+            normalize_url("https://example.com/a.png?utm_medium=rss", &strip()),
+            "https://example.com/a.png",
+        );
+        assert_eq!(
+            normalize_url("https://example.com/a.png?utm_medium=rss", &[]),
+            "https://example.com/a.png?utm_medium=rss",
+        );
+    }
+
+    #[test]
+    fn unparsable_urls_are_kept() {
+        assert_eq!(normalize_url("not a url", &strip()), "not a url");
+    }
+}
@@ -0,0 +1,21 @@
+/// Size the image is shrunk to before hashing; blurhash only keeps a few components.
+const THUMBNAIL_SIZE: u32 = 32;
+
+const COMPONENTS_X: u32 = 4;
+
+const COMPONENTS_Y: u32 = 3;
+
+/// Computes a blurhash placeholder for an encoded image, or `None` when it cannot be
+/// decoded.
+pub(crate) fn blurhash(bytes: &[u8]) -> Option<String> {
+    let image = image::load_from_memory(bytes).ok()?;
+    let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgba8();
+    blurhash::encode(
+        COMPONENTS_X,
+        COMPONENTS_Y,
+        thumbnail.width(),
+        thumbnail.height(),
+        thumbnail.as_raw(),
+    )
+    .ok()
+}
@@ -0,0 +1,123 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use worker::{Env, Headers, Result};
+
+use crate::{PutOptions, METADATA_HEADERS, PUT_HEADERS};
+
+/// Largest serialized write sent through `CACHE_QUEUE`: the 128 KB message limit, less
+/// room for the queue's own framing. Bigger writes are performed directly.
+const MAX_MESSAGE_BYTES: usize = 126_000;
+
+/// A pending R2 write. The body travels inside the message, since the origin response it
+/// came from is gone by the time the consumer runs.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct CacheWrite {
+    pub(crate) url: String,
+    pub(crate) format: Option<String>,
+    /// The origin response headers `put_in_r2` reads, and no others.
+    headers: Vec<(String, String)>,
+    body: String,
+    overwrite: bool,
+    #[serde(default)]
+    ttl: Option<u64>,
+    #[serde(default)]
+    tag: Option<String>,
+}
+
+impl CacheWrite {
+    pub(crate) fn headers(&self) -> Result<Headers> {
+        let headers = Headers::new();
+        for (name, value) in &self.headers {
+            headers.append(name, value)?;
+        }
+        Ok(headers)
+    }
+
+    pub(crate) fn options(&self) -> PutOptions {
+        PutOptions {
+            overwrite: self.overwrite,
+            ttl: self.ttl,
+            tag: self.tag.clone(),
+            derived: false,
+            key: None,
+        }
+    }
+
+    pub(crate) fn body(&self) -> Result<Vec<u8>> {
+        STANDARD
+            .decode(&self.body)
+            .map_err(|e| format!("invalid queued body: {e}").into())
+    }
+}
+
+/// Whether `write` fits in a single `CACHE_QUEUE` message once serialized.
+fn fits_in_message(write: &CacheWrite) -> bool {
+    serde_json::to_vec(write).is_ok_and(|message| message.len() <= MAX_MESSAGE_BYTES)
+}
+
+/// Hands the write to `CACHE_QUEUE`, returning `false` when no queue is bound or the write
+/// is too large for a message, in which case the caller has to write it itself.
+pub(crate) async fn enqueue(
+    env: &Env,
+    url: &str,
+    format: Option<&str>,
+    headers: &Headers,
+    body: &[u8],
+    options: &PutOptions,
+) -> Result<bool> {
+    let Ok(queue) = env.queue("CACHE_QUEUE") else {
+        return Ok(false);
+    };
+    // base64 grows the body by a third, so there is no point encoding one that cannot fit
+    if body.len() / 3 * 4 > MAX_MESSAGE_BYTES {
+        return Ok(false);
+    }
+    let mut kept = Vec::new();
+    for name in METADATA_HEADERS
+        .iter()
+        .map(|(_, name)| *name)
+        .chain(PUT_HEADERS.iter().copied())
+    {
+        if let Some(value) = headers.get(name)? {
+            kept.push((name.to_string(), value));
+        }
+    }
+    let write = CacheWrite {
+        url: url.to_string(),
+        format: format.map(str::to_string),
+        headers: kept,
+        body: STANDARD.encode(body),
+        overwrite: options.overwrite,
+        ttl: options.ttl,
+        tag: options.tag.clone(),
+    };
+    if !fits_in_message(&write) {
+        return Ok(false);
+    }
+    queue.send(write).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_of(body_len: usize, header_len: usize) -> CacheWrite {
+        CacheWrite {
+            url: "https://example.com/a.png".to_string(),
+            format: None,
+            headers: vec![("Content-Disposition".to_string(), "x".repeat(header_len))],
+            body: STANDARD.encode(vec![0u8; body_len]),
+            overwrite: false,
+            ttl: None,
+            tag: None,
+        }
+    }
+
+    #[test]
+    fn message_budget_counts_the_serialized_write() {
+        assert!(fits_in_message(&write_of(90 * 1024, 0)));
+        // a body that fits on its own no longer does next to large headers
+        assert!(!fits_in_message(&write_of(90 * 1024, 8 * 1024)));
+        assert!(!fits_in_message(&write_of(95 * 1024, 0)));
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+
+use worker::js_sys::{Function, Object, Promise, Reflect};
+use worker::wasm_bindgen::{JsCast, JsValue};
+use worker::wasm_bindgen_futures::JsFuture;
+use worker::{Bucket, Data, FixedLengthStream, HttpMetadata, ObjectBody, Result};
+
+/// The `size` bytes of a stored object's `body` as data for a put, streamed rather than
+/// read into memory, so that rewriting metadata costs no more for large objects.
+pub(crate) fn restream(body: ObjectBody, size: u64) -> Result<Data> {
+    Ok(Data::Stream(FixedLengthStream::wrap(body.stream()?, size)))
+}
+
+/// Writes `body` to `key` only if the stored object still has `etag`, returning whether
+/// it was written.
+///
+/// The `worker` crate has no `onlyIf` option for puts, so this calls the binding
+/// directly. R2 answers a failed precondition with `null` rather than an error.
+pub(crate) async fn put_if_match(
+    bucket: Bucket,
+    key: &str,
+    body: Data,
+    http_metadata: HttpMetadata,
+    metadata: HashMap<String, String>,
+    etag: &str,
+) -> Result<bool> {
+    let bucket = JsValue::from(bucket);
+    let put: Function = Reflect::get(&bucket, &"put".into())?.dyn_into()?;
+    let custom_metadata = Object::new();
+    for (field, value) in metadata {
+        Reflect::set(&custom_metadata, &field.into(), &value.into())?;
+    }
+    let only_if = Object::new();
+    Reflect::set(&only_if, &"etagMatches".into(), &etag.into())?;
+    let options = Object::new();
+    Reflect::set(
+        &options,
+        &"httpMetadata".into(),
+        &Object::from(http_metadata),
+    )?;
+    Reflect::set(&options, &"customMetadata".into(), &custom_metadata)?;
+    Reflect::set(&options, &"onlyIf".into(), &only_if)?;
+    let value = JsValue::from(body);
+    let promise: Promise = put
+        .call3(&bucket, &key.into(), &value, &options)?
+        .dyn_into()?;
+    Ok(!JsFuture::from(promise).await?.is_null())
+}
@@ -0,0 +1,56 @@
+/// A single byte range from a `Range: bytes=...` request header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ByteRange {
+    /// `bytes=start-end`, both inclusive.
+    FromTo(u64, u64),
+    /// `bytes=start-`
+    From(u64),
+    /// `bytes=-length`
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Parses a `Range` header. Multi-range and non-byte requests are not supported and
+    /// yield `None`, which callers treat as a plain full-body request.
+    pub(crate) fn parse(header: &str) -> Option<Self> {
+        let spec = header.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+        let (start, end) = spec.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+        match (start.is_empty(), end.is_empty()) {
+            (true, false) => Some(Self::Suffix(end.parse().ok()?)),
+            (false, true) => Some(Self::From(start.parse().ok()?)),
+            (false, false) => {
+                let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+                (start <= end).then_some(Self::FromTo(start, end))
+            }
+            (true, true) => None,
+        }
+    }
+
+    /// Resolves the range against an object of `size` bytes into inclusive `(start, end)`
+    /// offsets, or `None` when the range is unsatisfiable.
+    pub(crate) fn resolve(self, size: u64) -> Option<(u64, u64)> {
+        let last = size.checked_sub(1)?;
+        match self {
+            Self::FromTo(start, end) if start <= last => Some((start, end.min(last))),
+            Self::From(start) if start <= last => Some((start, last)),
+            Self::Suffix(length) if length > 0 => Some((size.saturating_sub(length), last)),
+            _ => None,
+        }
+    }
+}
+
+/// The object size from a `Content-Range: bytes start-end/size` response header when the
+/// range spans the whole object, as some CDNs send `206` even to requests without a
+/// `Range`. `None` for a true part of an object or an unknown size.
+pub(crate) fn complete_length(content_range: &str) -> Option<u64> {
+    let spec = content_range.trim().strip_prefix("bytes ")?;
+    let (range, size) = spec.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end): (u64, u64) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    let size: u64 = size.trim().parse().ok()?;
+    (start == 0 && end.checked_add(1) == Some(size)).then_some(size)
+}
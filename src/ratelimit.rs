@@ -0,0 +1,74 @@
+use std::cell::Cell;
+
+use worker::{durable_object, Date, DurableObject, Env, Request, Response, Result, State};
+// `#[durable_object]` expands to `wasm_bindgen` paths, which would otherwise need the
+// crate as a direct dependency
+use worker::wasm_bindgen;
+
+/// A token bucket per origin host, refilled at `PER_HOST_RPS` tokens per second.
+///
+/// One instance exists per host. Each `/take` spends a token when one is available and
+/// answers `ok`, or answers `limited` without waiting. The bucket holds at most one
+/// second's worth of tokens, so bursts are capped at `PER_HOST_RPS` requests.
+#[durable_object]
+pub struct HostRateLimiter {
+    tokens: Cell<f64>,
+    refilled_at: Cell<u64>,
+}
+
+impl DurableObject for HostRateLimiter {
+    fn new(_state: State, _env: Env) -> Self {
+        Self {
+            tokens: Cell::new(f64::NAN),
+            refilled_at: Cell::new(0),
+        }
+    }
+
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        if req.path() != "/take" {
+            return Response::error("Not Found", 404);
+        }
+        let rps: f64 = req
+            .url()?
+            .query_pairs()
+            .find(|(name, _)| name == "rps")
+            .and_then(|(_, value)| value.parse().ok())
+            .unwrap_or(f64::INFINITY);
+        let now = Date::now().as_millis();
+        let tokens = if self.tokens.get().is_nan() {
+            rps
+        } else {
+            let elapsed = now.saturating_sub(self.refilled_at.get()) as f64 / 1000.0;
+            (self.tokens.get() + elapsed * rps).min(rps)
+        };
+        self.refilled_at.set(now);
+        if tokens >= 1.0 {
+            self.tokens.set(tokens - 1.0);
+            Response::ok("ok")
+        } else {
+            self.tokens.set(tokens);
+            Response::ok("limited")
+        }
+    }
+}
+
+/// Spends a token for `host`, returning `false` when it is over `PER_HOST_RPS`.
+///
+/// Requests are always allowed when no limit is configured or the limiter is not bound.
+pub(crate) async fn try_acquire(env: &Env, host: &str) -> Result<bool> {
+    let Some(rps) = env
+        .var("PER_HOST_RPS")
+        .ok()
+        .and_then(|v| v.to_string().parse::<f64>().ok())
+    else {
+        return Ok(true);
+    };
+    let Ok(namespace) = env.durable_object("HOST_RATE_LIMITER") else {
+        return Ok(true);
+    };
+    let stub = namespace.get_by_name(host)?;
+    let mut res = stub
+        .fetch_with_str(&format!("https://host-rate-limiter/take?rps={rps}"))
+        .await?;
+    Ok(res.text().await? != "limited")
+}
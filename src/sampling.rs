@@ -0,0 +1,63 @@
+use tracing::{
+    field::{Field, Visit},
+    span, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    filter::{dynamic_filter_fn, DynFilterFn},
+    layer::{Context, Layer},
+    registry::LookupSpan,
+};
+
+/// Name of the field on the per-request span carrying the sampling decision.
+pub(crate) const SAMPLED_FIELD: &str = "sampled";
+
+/// The sampling decision of a request span, stored in its extensions.
+struct Sampled(bool);
+
+/// Records the `sampled` field of new spans so [`filter`] can find it later.
+pub(crate) struct SamplingLayer;
+
+impl<S> Layer<S> for SamplingLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut visitor = SampledVisitor(None);
+        attrs.record(&mut visitor);
+        if let (Some(sampled), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().insert(Sampled(sampled));
+        }
+    }
+}
+
+struct SampledVisitor(Option<bool>);
+
+impl Visit for SampledVisitor {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == SAMPLED_FIELD {
+            self.0 = Some(value);
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+/// Drops info and lower events inside a request span that was not sampled. Warnings and
+/// errors always pass, as does anything logged outside a request.
+pub(crate) fn filter<S>() -> DynFilterFn<S, impl Fn(&Metadata<'_>, &Context<'_, S>) -> bool>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    dynamic_filter_fn(|metadata, ctx| {
+        if !metadata.is_event() || *metadata.level() <= Level::WARN {
+            return true;
+        }
+        let Some(current) = ctx.lookup_current() else {
+            return true;
+        };
+        current
+            .scope()
+            .find_map(|span| span.extensions().get::<Sampled>().map(|sampled| sampled.0))
+            .unwrap_or(true)
+    })
+}
@@ -0,0 +1,45 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Checks that `sig` is the hex-encoded HMAC-SHA256 of `message` under `key`.
+///
+/// The comparison is constant-time; a signature that is not valid hex never matches.
+pub(crate) fn verify(key: &str, message: &str, sig: &str) -> bool {
+    let mut buf = [0u8; 32];
+    let Ok(sig) = base16ct::mixed::decode(sig, &mut buf) else {
+        return false;
+    };
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(message.as_bytes());
+    mac.verify_slice(sig).is_ok()
+}
+
+/// Compares two secrets without short-circuiting on the first differing byte.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Checks an `Authorization` header value against the `Basic` credentials `user` and
+/// `pass`. Both halves are always compared so timing does not reveal which one differed.
+pub(crate) fn verify_basic_auth(header: &str, user: &str, pass: &str) -> bool {
+    let Some((scheme, encoded)) = header.trim().split_once(' ') else {
+        return false;
+    };
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return false;
+    }
+    let Ok(decoded) = STANDARD.decode(encoded.trim()) else {
+        return false;
+    };
+    // the user id cannot contain a colon, but the password may
+    let Some(colon) = decoded.iter().position(|&b| b == b':') else {
+        return false;
+    };
+    let (given_user, given_pass) = (&decoded[..colon], &decoded[colon + 1..]);
+    let user_ok = constant_time_eq(given_user, user.as_bytes());
+    let pass_ok = constant_time_eq(given_pass, pass.as_bytes());
+    user_ok & pass_ok
+}
@@ -0,0 +1,317 @@
+/// How many leading bytes are searched for an SVG root element.
+const SVG_SNIFF_BYTES: usize = 512;
+
+/// Guesses an image `Content-Type` from the leading magic bytes of `bytes`.
+pub(crate) fn image_content_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if bytes.len() >= 12
+        && &bytes[4..8] == b"ftyp"
+        && matches!(&bytes[8..12], b"avif" | b"avis")
+    {
+        Some("image/avif")
+    } else if is_svg(&bytes[..bytes.len().min(SVG_SNIFF_BYTES)]) {
+        Some("image/svg+xml")
+    } else {
+        None
+    }
+}
+
+/// Looks for an `<svg` root element, allowing for an XML prolog, doctype or comments.
+fn is_svg(head: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    head.starts_with('<') && head.contains("<svg")
+}
+
+/// Reads the pixel dimensions from the header of a PNG, JPEG, GIF or WebP image.
+pub(crate) fn image_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    match image_content_type(bytes)? {
+        "image/png" if bytes.get(12..16)? == b"IHDR" => {
+            Some((be_u32(bytes.get(16..20)?), be_u32(bytes.get(20..24)?)))
+        }
+        "image/gif" => Some((le_u16(bytes.get(6..8)?), le_u16(bytes.get(8..10)?))),
+        "image/jpeg" => jpeg_dimensions(bytes),
+        "image/webp" => webp_dimensions(bytes),
+        _ => None,
+    }
+}
+
+/// Whether `bytes` hold an animation: a GIF with more than one image, a WebP with an
+/// `ANIM` chunk, or a PNG with an `acTL` chunk (APNG).
+pub(crate) fn is_animated(bytes: &[u8]) -> bool {
+    match image_content_type(bytes) {
+        Some("image/gif") => gif_frames(bytes) > 1,
+        Some("image/webp") => webp_is_animated(bytes),
+        Some("image/png") => png_is_animated(bytes),
+        _ => false,
+    }
+}
+
+/// Counts image descriptors, stopping at the second as that is all [`is_animated`] needs.
+/// A truncated file counts the frames seen so far.
+fn gif_frames(bytes: &[u8]) -> usize {
+    let Some(&packed) = bytes.get(10) else {
+        return 0;
+    };
+    let mut i = 13 + color_table_len(packed);
+    let mut frames = 0;
+    while frames < 2 {
+        match bytes.get(i) {
+            // extension: introducer, label, then data sub-blocks
+            Some(0x21) => i += 2,
+            // image descriptor: 10 bytes, a local color table, the LZW code size, then data
+            Some(0x2c) => {
+                frames += 1;
+                let Some(&packed) = bytes.get(i + 9) else {
+                    break;
+                };
+                i += 10 + color_table_len(packed) + 1;
+            }
+            _ => break,
+        }
+        let Some(end) = skip_sub_blocks(bytes, i) else {
+            break;
+        };
+        i = end;
+    }
+    frames
+}
+
+/// Size of the color table announced by a GIF packed-fields byte.
+fn color_table_len(packed: u8) -> usize {
+    if packed & 0x80 == 0 {
+        0
+    } else {
+        3 << ((packed & 0x07) + 1)
+    }
+}
+
+/// Returns the offset just past the zero-length terminator of the sub-blocks at `i`.
+fn skip_sub_blocks(bytes: &[u8], mut i: usize) -> Option<usize> {
+    loop {
+        let len = usize::from(*bytes.get(i)?);
+        i += 1;
+        if len == 0 {
+            return Some(i);
+        }
+        i += len;
+    }
+}
+
+fn webp_is_animated(bytes: &[u8]) -> bool {
+    // the VP8X header flags animation; the ANIM chunk is checked too in case it lies
+    if bytes.get(12..16) == Some(b"VP8X") && bytes.get(20).is_some_and(|flags| flags & 0x02 != 0) {
+        return true;
+    }
+    let mut i = 12;
+    while let Some(header) = bytes.get(i..i + 8) {
+        if &header[0..4] == b"ANIM" {
+            return true;
+        }
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let Ok(len) = usize::try_from(len) else {
+            return false;
+        };
+        // chunks are padded to an even length
+        let Some(next) = skip_chunk(bytes, i, len, 8 + (len & 1)) else {
+            return false;
+        };
+        i = next;
+    }
+    false
+}
+
+fn png_is_animated(bytes: &[u8]) -> bool {
+    let mut i = 8;
+    while let Some(header) = bytes.get(i..i + 8) {
+        match &header[4..8] {
+            b"acTL" => return true,
+            // acTL must come before the image data
+            b"IDAT" => return false,
+            _ => {}
+        }
+        let Ok(len) = usize::try_from(be_u32(&header[0..4])) else {
+            return false;
+        };
+        // length, type, data and CRC
+        let Some(next) = skip_chunk(bytes, i, len, 12) else {
+            return false;
+        };
+        i = next;
+    }
+    false
+}
+
+/// The offset of the chunk after the one at `i` with `len` data bytes and `overhead`
+/// bytes of framing, or `None` when that is past the end of `bytes`. The length comes
+/// from the file, so the sum is checked: on wasm32 it could otherwise wrap back to `i`
+/// and loop forever.
+fn skip_chunk(bytes: &[u8], i: usize, len: usize, overhead: usize) -> Option<usize> {
+    let next = i.checked_add(len)?.checked_add(overhead)?;
+    (next <= bytes.len()).then_some(next)
+}
+
+fn be_u32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn be_u16(b: &[u8]) -> u32 {
+    u32::from(u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn le_u16(b: &[u8]) -> u32 {
+    u32::from(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn le_u24(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], 0])
+}
+
+/// Walks the JPEG marker segments up to the first start-of-frame.
+fn jpeg_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let mut i = 2;
+    loop {
+        while *bytes.get(i)? != 0xff {
+            i += 1;
+        }
+        while *bytes.get(i)? == 0xff {
+            i += 1;
+        }
+        let marker = *bytes.get(i)?;
+        i += 1;
+        match marker {
+            // standalone markers carry no length
+            0x01 | 0xd0..=0xd8 => continue,
+            0xc0..=0xcf if !matches!(marker, 0xc4 | 0xc8 | 0xcc) => {
+                let height = be_u16(bytes.get(i + 3..i + 5)?);
+                let width = be_u16(bytes.get(i + 5..i + 7)?);
+                return Some((width, height));
+            }
+            _ => i += usize::try_from(be_u16(bytes.get(i..i + 2)?)).ok()?,
+        }
+    }
+}
+
+fn webp_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    match bytes.get(12..16)? {
+        b"VP8 " => Some((
+            le_u16(bytes.get(26..28)?) & 0x3fff,
+            le_u16(bytes.get(28..30)?) & 0x3fff,
+        )),
+        b"VP8L" => {
+            let b = bytes.get(21..25)?;
+            let (b0, b1, b2, b3) = (
+                u32::from(b[0]),
+                u32::from(b[1]),
+                u32::from(b[2]),
+                u32::from(b[3]),
+            );
+            Some((
+                1 + (b0 | (b1 & 0x3f) << 8),
+                1 + (b1 >> 6 | b2 << 2 | (b3 & 0x0f) << 10),
+            ))
+        }
+        b"VP8X" => Some((
+            1 + le_u24(bytes.get(24..27)?),
+            1 + le_u24(bytes.get(27..30)?),
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&[0; 4]);
+        chunk
+    }
+
+    fn png(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend(png_chunk(b"IHDR", &[0; 13]));
+        for (kind, data) in chunks {
+            png.extend(png_chunk(kind, data));
+        }
+        png
+    }
+
+    fn webp(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut webp = b"RIFF\0\0\0\0WEBP".to_vec();
+        for (kind, data) in chunks {
+            webp.extend_from_slice(*kind);
+            webp.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            webp.extend_from_slice(data);
+            if data.len() % 2 == 1 {
+                webp.push(0);
+            }
+        }
+        webp
+    }
+
+    fn gif(frames: usize) -> Vec<u8> {
+        // header and a logical screen descriptor without a global color table
+        let mut gif = b"GIF89a\x01\0\x01\0\0\0\0".to_vec();
+        for _ in 0..frames {
+            // graphic control extension, then an image descriptor and its data
+            gif.extend_from_slice(b"\x21\xf9\x04\0\0\0\0\0");
+            gif.extend_from_slice(b"\x2c\0\0\0\0\x01\0\x01\0\0");
+            gif.extend_from_slice(b"\x02\x02\x44\x01\0");
+        }
+        gif.push(0x3b);
+        gif
+    }
+
+    #[test]
+    fn gif_with_several_frames_is_animated() {
+        assert!(!is_animated(&gif(1)));
+        assert!(is_animated(&gif(2)));
+        assert!(is_animated(&gif(3)));
+    }
+
+    #[test]
+    fn apng_is_animated_when_actl_precedes_idat() {
+        assert!(is_animated(&png(&[(b"acTL", &[0; 8]), (b"IDAT", &[0; 4])])));
+        assert!(!is_animated(&png(&[(b"IDAT", &[0; 4])])));
+        assert!(!is_animated(&png(&[
+            (b"IDAT", &[0; 4]),
+            (b"acTL", &[0; 8])
+        ])));
+    }
+
+    #[test]
+    fn webp_is_animated_by_flag_or_anim_chunk() {
+        let mut flags = [0; 10];
+        assert!(!is_animated(&webp(&[
+            (b"VP8X", &flags),
+            (b"VP8 ", &[0; 3])
+        ])));
+        assert!(is_animated(&webp(&[(b"VP8X", &flags), (b"ANIM", &[0; 6])])));
+        flags[0] = 0x02;
+        assert!(is_animated(&webp(&[(b"VP8X", &flags)])));
+    }
+
+    #[test]
+    fn huge_chunk_lengths_end_the_scan() {
+        let mut png = png(&[]);
+        png.extend_from_slice(&0xffff_fff4_u32.to_be_bytes());
+        png.extend_from_slice(b"tEXt");
+        assert!(!is_animated(&png));
+        let mut webp = webp(&[(b"VP8X", &[0; 10])]);
+        webp.extend_from_slice(b"EXIF");
+        webp.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(!is_animated(&webp));
+        assert_eq!(skip_chunk(&[0; 16], 8, usize::MAX - 4, 12), None);
+    }
+}
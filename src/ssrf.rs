@@ -0,0 +1,150 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use worker::Url;
+
+/// Parses `url_str` and applies [`check_url`], describing why it is rejected when empty,
+/// relative or malformed.
+pub(crate) fn check(url_str: &str, allowed_hosts: &[String]) -> Result<(), String> {
+    if url_str.trim().is_empty() {
+        return Err("url is empty".to_string());
+    }
+    let url = match Url::parse(url_str) {
+        Ok(url) => url,
+        Err(url::ParseError::RelativeUrlWithoutBase) => {
+            return Err("url must be absolute, with an http or https scheme".to_string());
+        }
+        Err(e) => return Err(format!("invalid url: {e}")),
+    };
+    check_url(&url, allowed_hosts)
+}
+
+/// Rejects URLs that could reach the worker's own network or other non-public hosts.
+///
+/// Workers cannot resolve DNS, so only literal IP addresses and well-known internal
+/// hostnames are checked. Hosts listed in `allowed_hosts` skip the check entirely.
+fn check_url(url: &Url, allowed_hosts: &[String]) -> Result<(), String> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!(
+            "scheme {} is not allowed, only http and https",
+            url.scheme()
+        ));
+    }
+    let Some(host) = url.host() else {
+        return Err("url has no host".to_string());
+    };
+    let host_str = url.host_str().unwrap_or_default().to_ascii_lowercase();
+    if allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(&host_str))
+    {
+        return Ok(());
+    }
+    let blocked = match host {
+        url::Host::Ipv4(ip) => is_internal_ipv4(ip),
+        url::Host::Ipv6(ip) => is_internal_ipv6(ip),
+        url::Host::Domain(domain) => is_internal_domain(&domain.to_ascii_lowercase()),
+    };
+    if blocked {
+        return Err(format!("host {host_str} is not allowed"));
+    }
+    Ok(())
+}
+
+fn is_internal_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        // carrier-grade NAT, 100.64.0.0/10
+        || (a == 100 && (64..128).contains(&b))
+}
+
+fn is_internal_ipv6(ip: Ipv6Addr) -> bool {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_internal_ipv4(v4);
+    }
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // unique local, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // link-local, fe80::/10
+        || (first & 0xffc0) == 0xfe80
+}
+
+fn is_internal_domain(domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+    domain == "localhost"
+        || domain.ends_with(".localhost")
+        || domain.ends_with(".local")
+        || domain.ends_with(".internal")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reason(url_str: &str) -> String {
+        check(url_str, &[]).unwrap_err()
+    }
+
+    #[test]
+    fn public_http_urls_are_allowed() {
+        assert_eq!(check("https://example.com/a.png", &[]), Ok(()));
+        assert_eq!(check("http://93.184.216.34/a.png", &[]), Ok(()));
+    }
+
+    #[test]
+    fn other_schemes_are_rejected() {
+        assert_eq!(
+            reason("ftp://example.com/a.png"),
+            "scheme ftp is not allowed, only http and https",
+        );
+        assert_eq!(
+            reason("data:image/png;base64,iVBORw0KGgo="),
+            "scheme data is not allowed, only http and https",
+        );
+        assert_eq!(
+            reason("file:///etc/passwd"),
+            "scheme file is not allowed, only http and https",
+        );
+    }
+
+    #[test]
+    fn malformed_urls_are_rejected() {
+        assert_eq!(reason(""), "url is empty");
+        assert_eq!(reason("  "), "url is empty");
+        assert_eq!(
+            reason("/images/a.png"),
+            "url must be absolute, with an http or https scheme",
+        );
+        assert_eq!(
+            reason("example.com/a.png"),
+            "url must be absolute, with an http or https scheme",
+        );
+        assert!(reason("https://exa mple.com/").starts_with("invalid url: "));
+        assert!(reason("http://[::1/").starts_with("invalid url: "));
+    }
+
+    #[test]
+    fn internal_hosts_are_rejected_unless_allowed() {
+        for url in [
+            "http://127.0.0.1/",
+            "http://10.1.2.3/",
+            "http://100.64.0.1/",
+            "http://[::1]/",
+            "http://[::ffff:192.168.0.1]/",
+            "http://[fd00::1]/",
+            "http://localhost:8080/",
+            "http://metadata.google.internal/",
+        ] {
+            assert!(check(url, &[]).is_err(), "{url}");
+        }
+        assert_eq!(
+            check("http://localhost/", &["LOCALHOST".to_string()]),
+            Ok(())
+        );
+    }
+}
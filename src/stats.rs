@@ -0,0 +1,273 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use time::{Duration, OffsetDateTime};
+use worker::{KvStore, Result};
+
+use crate::Shared;
+
+/// KV key holding the all-time counters.
+const TOTAL_KEY: &str = "stats:total";
+
+/// Upper bounds, in milliseconds, of the origin latency histogram buckets; slower
+/// fetches land in a final unbounded bucket.
+const LATENCY_BUCKETS_MS: [u64; 8] = [50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// A cache outcome counted by [`Counters::count`].
+#[derive(Clone, Copy)]
+pub(crate) enum Event {
+    Hit,
+    OriginFetch,
+    Fallback,
+    Error,
+    /// A response was sent for an image request, with what is known about it.
+    Served {
+        miss: bool,
+        bytes: Option<u64>,
+        origin_latency_ms: Option<u64>,
+    },
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Counters {
+    #[serde(default)]
+    hit: u64,
+    #[serde(default)]
+    origin_fetch: u64,
+    #[serde(default)]
+    fallback: u64,
+    #[serde(default)]
+    error: u64,
+    #[serde(default)]
+    miss: u64,
+    /// Sum of the `Content-Length` of responses that declared one.
+    #[serde(default)]
+    bytes_served: u64,
+    /// Origin fetches per [`LATENCY_BUCKETS_MS`] bucket, not cumulative, the unbounded
+    /// bucket last.
+    #[serde(default)]
+    origin_latency_buckets: Vec<u64>,
+    #[serde(default)]
+    origin_latency_ms_sum: u64,
+}
+
+impl Counters {
+    pub(crate) fn count(&mut self, event: Event) {
+        let counter = match event {
+            Event::Hit => &mut self.hit,
+            Event::OriginFetch => &mut self.origin_fetch,
+            Event::Fallback => &mut self.fallback,
+            Event::Error => &mut self.error,
+            Event::Served {
+                miss,
+                bytes,
+                origin_latency_ms,
+            } => {
+                self.bytes_served += bytes.unwrap_or(0);
+                if let Some(latency_ms) = origin_latency_ms {
+                    self.observe_origin_latency(latency_ms);
+                }
+                if !miss {
+                    return;
+                }
+                &mut self.miss
+            }
+        };
+        *counter += 1;
+    }
+
+    /// Adds the counts of `delta`.
+    fn add(&mut self, delta: &Counters) {
+        self.hit += delta.hit;
+        self.origin_fetch += delta.origin_fetch;
+        self.fallback += delta.fallback;
+        self.error += delta.error;
+        self.miss += delta.miss;
+        self.bytes_served += delta.bytes_served;
+        if self.origin_latency_buckets.len() < delta.origin_latency_buckets.len() {
+            self.origin_latency_buckets
+                .resize(delta.origin_latency_buckets.len(), 0);
+        }
+        for (total, count) in self
+            .origin_latency_buckets
+            .iter_mut()
+            .zip(&delta.origin_latency_buckets)
+        {
+            *total += count;
+        }
+        self.origin_latency_ms_sum += delta.origin_latency_ms_sum;
+    }
+
+    fn observe_origin_latency(&mut self, latency_ms: u64) {
+        self.origin_latency_buckets
+            .resize(LATENCY_BUCKETS_MS.len() + 1, 0);
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| latency_ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.origin_latency_buckets[bucket] += 1;
+        self.origin_latency_ms_sum += latency_ms;
+    }
+
+    /// Renders the counters in the Prometheus text exposition format.
+    pub(crate) fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("hits", "Requests served from a cache.", self.hit),
+            ("misses", "Requests not served from a cache.", self.miss),
+            (
+                "fallbacks",
+                "Requests answered with the fallback image.",
+                self.fallback,
+            ),
+            (
+                "errors",
+                "Requests that failed with an internal error.",
+                self.error,
+            ),
+            (
+                "bytes_served",
+                "Bytes of responses with a Content-Length.",
+                self.bytes_served,
+            ),
+            (
+                "origin_fetches",
+                "Fetches from origin servers.",
+                self.origin_fetch,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let name = format!("image_cache_{name}_total");
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {value}");
+        }
+        let name = "image_cache_origin_latency_seconds";
+        let _ = writeln!(out, "# HELP {name} Time taken by origin fetches.");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (i, bound) in LATENCY_BUCKETS_MS.iter().enumerate() {
+            cumulative += self.origin_latency_buckets.get(i).copied().unwrap_or(0);
+            let le = *bound as f64 / 1000.0;
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}");
+        }
+        cumulative += self
+            .origin_latency_buckets
+            .get(LATENCY_BUCKETS_MS.len())
+            .copied()
+            .unwrap_or(0);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {cumulative}");
+        let sum = self.origin_latency_ms_sum as f64 / 1000.0;
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {cumulative}");
+        out
+    }
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Stats {
+    total: Counters,
+    days: BTreeMap<String, Counters>,
+}
+
+fn day_key(day: time::Date) -> String {
+    format!("stats:day:{day}")
+}
+
+fn today() -> time::Date {
+    let now = (worker::Date::now().as_millis() / 1000) as i64;
+    OffsetDateTime::from_unix_timestamp(now)
+        .expect("current time is a valid timestamp")
+        .date()
+}
+
+/// Adds the events a request counted in `delta` to `STATS_KV` after the response has been
+/// sent. Does nothing when the namespace is not bound.
+///
+/// Each request is one read-modify-write per key, however many events it counted, which
+/// keeps within KV's write rate per key. Concurrent requests may still lose a count.
+pub(crate) fn record(ctx: &Shared, delta: Counters) {
+    let Ok(kv) = ctx.env.kv("STATS_KV") else {
+        return;
+    };
+    ctx.data.wait_until(async move {
+        for key in [TOTAL_KEY.to_string(), day_key(today())] {
+            if let Err(e) = increment(&kv, &key, &delta).await {
+                tracing::warn!(key = key, error = %e, "failed to update stats");
+            }
+        }
+    });
+}
+
+async fn increment(kv: &KvStore, key: &str, delta: &Counters) -> Result<()> {
+    let mut counters: Counters = kv.get(key).json().await?.unwrap_or_default();
+    counters.add(delta);
+    kv.put(key, serde_json::to_string(&counters)?)?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Loads the all-time counters.
+pub(crate) async fn load_total(kv: &KvStore) -> Result<Counters> {
+    Ok(kv.get(TOTAL_KEY).json().await?.unwrap_or_default())
+}
+
+/// Loads the all-time counters and those of the last `days` UTC days.
+pub(crate) async fn load(kv: &KvStore, days: u32) -> Result<Stats> {
+    let total = load_total(kv).await?;
+    let today = today();
+    let mut by_day = BTreeMap::new();
+    for offset in 0..days {
+        let day = today - Duration::days(offset.into());
+        if let Some(counters) = kv.get(&day_key(day)).json().await? {
+            by_day.insert(day.to_string(), counters);
+        }
+    }
+    Ok(Stats {
+        total,
+        days: by_day,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_request_adds_all_its_events_at_once() {
+        let mut delta = Counters::default();
+        delta.count(Event::OriginFetch);
+        delta.count(Event::Served {
+            miss: true,
+            bytes: Some(1000),
+            origin_latency_ms: Some(120),
+        });
+        let mut total = Counters {
+            hit: 3,
+            bytes_served: 500,
+            ..Default::default()
+        };
+        total.add(&delta);
+        total.add(&delta);
+        assert_eq!(total.hit, 3);
+        assert_eq!(total.origin_fetch, 2);
+        assert_eq!(total.miss, 2);
+        assert_eq!(total.bytes_served, 2500);
+        assert_eq!(total.origin_latency_ms_sum, 240);
+        assert_eq!(total.origin_latency_buckets[2], 2);
+        assert_eq!(total.origin_latency_buckets.iter().sum::<u64>(), 2);
+    }
+
+    #[test]
+    fn hits_are_not_misses() {
+        let mut counters = Counters::default();
+        counters.count(Event::Hit);
+        counters.count(Event::Served {
+            miss: false,
+            bytes: None,
+            origin_latency_ms: None,
+        });
+        assert_eq!((counters.hit, counters.miss), (1, 0));
+        assert!(counters.origin_latency_buckets.is_empty());
+    }
+}
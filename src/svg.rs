@@ -0,0 +1,251 @@
+use worker::{Headers, Result};
+
+/// `Content-Security-Policy` for sanitized SVGs: anything the sanitizer missed still
+/// cannot run script or load external resources when the SVG is opened directly.
+pub(crate) const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'none'; style-src 'unsafe-inline'; img-src data:";
+
+/// Elements removed together with everything inside them.
+const DROPPED_ELEMENTS: &[&str] = &[
+    "script",
+    "foreignobject",
+    "iframe",
+    "embed",
+    "object",
+    "handler",
+    "listener",
+];
+
+/// Removes script from an SVG document while keeping the rest of the vector content.
+///
+/// Like the feed scanner this is a tag scanner rather than an XML parser. It drops
+/// [`DROPPED_ELEMENTS`], comments, doctypes (which may declare external entities) and
+/// processing instructions other than the XML declaration; event handler attributes;
+/// attributes referring to `javascript:` or to anything outside the document, `data:`
+/// images excepted; and `<style>` blocks that import or reference external resources.
+pub(crate) fn sanitize(svg: &[u8]) -> Vec<u8> {
+    let svg = String::from_utf8_lossy(svg);
+    let mut out = String::with_capacity(svg.len());
+    let mut rest: &str = &svg;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("<!--") {
+            rest = after.find("-->").map_or("", |end| &after[end + 3..]);
+        } else if rest.starts_with("<![CDATA[") {
+            let end = rest.find("]]>").map_or(rest.len(), |end| end + 3);
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if rest.starts_with("<!") {
+            // an internal subset in brackets may itself contain `>`
+            let end = match (rest.find('['), rest.find('>')) {
+                (Some(bracket), Some(close)) if bracket < close => rest.find("]>").map(|i| i + 2),
+                (_, close) => close.map(|i| i + 1),
+            };
+            rest = &rest[end.unwrap_or(rest.len())..];
+        } else if rest.starts_with("<?") {
+            let end = rest.find("?>").map_or(rest.len(), |end| end + 2);
+            if rest.starts_with("<?xml ") {
+                out.push_str(&rest[..end]);
+            }
+            rest = &rest[end..];
+        } else if rest.starts_with("</") {
+            let end = tag_end(rest);
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else {
+            let end = tag_end(rest);
+            let inner = rest[1..end].strip_suffix('>').unwrap_or(&rest[1..end]);
+            rest = &rest[end..];
+            let self_closing = inner.trim_end().ends_with('/');
+            let name = tag_name(inner);
+            let local = local_name(name).to_ascii_lowercase();
+            if DROPPED_ELEMENTS.contains(&local.as_str()) {
+                if !self_closing {
+                    rest = skip_element(rest, name);
+                }
+                continue;
+            }
+            if local == "style" && !self_closing {
+                let content_end = closing_tag(rest, name).unwrap_or(rest.len());
+                let content = rest[..content_end].to_ascii_lowercase();
+                if content.contains("@import") || has_external_url(&content) {
+                    rest = skip_element(rest, name);
+                    continue;
+                }
+            }
+            out.push('<');
+            out.push_str(&sanitize_tag(inner, name));
+            out.push('>');
+        }
+    }
+    out.push_str(rest);
+    out.into_bytes()
+}
+
+/// The offset just past the `>` closing the tag at the start of `s`, ignoring any `>`
+/// inside quoted attribute values.
+fn tag_end(s: &str) -> usize {
+    let mut quote = None;
+    for (i, c) in s.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, '>') => return i + 1,
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+fn tag_name(inner: &str) -> &str {
+    let end = inner
+        .find(|c: char| c.is_ascii_whitespace() || c == '/')
+        .unwrap_or(inner.len());
+    &inner[..end]
+}
+
+/// The name without its namespace prefix, e.g. `href` for `xlink:href`.
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+/// The offset of the closing tag for `name` in `s`, if there is one.
+fn closing_tag(s: &str, name: &str) -> Option<usize> {
+    // ASCII lowercasing keeps byte offsets the same
+    s.to_ascii_lowercase()
+        .find(&format!("</{}", name.to_ascii_lowercase()))
+}
+
+/// Returns what follows the closing tag for `name`, dropping the rest of the document
+/// when the element is never closed.
+fn skip_element<'a>(s: &'a str, name: &str) -> &'a str {
+    match closing_tag(s, name) {
+        Some(start) => &s[start + tag_end(&s[start..])..],
+        None => "",
+    }
+}
+
+/// Rebuilds the inside of a start tag with only its safe attributes.
+fn sanitize_tag(inner: &str, name: &str) -> String {
+    let mut out = name.to_string();
+    let mut rest = &inner[name.len()..];
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() || rest == "/" {
+            break;
+        }
+        let name_end = match rest.find(|c: char| c.is_ascii_whitespace() || c == '=' || c == '/') {
+            // a stray `=` or `/`, consumed on its own
+            Some(0) => rest.chars().next().map_or(0, char::len_utf8),
+            Some(end) => end,
+            None => rest.len(),
+        };
+        let attr_name = &rest[..name_end];
+        let after_name = rest[name_end..].trim_start();
+        let Some(value) = after_name.strip_prefix('=') else {
+            rest = &rest[name_end..];
+            if !is_event_handler(attr_name) && attr_name != "/" {
+                out.push(' ');
+                out.push_str(attr_name);
+            }
+            continue;
+        };
+        let value = value.trim_start();
+        let value_end = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].find(quote).map_or(value.len(), |i| i + 2),
+            _ => value.find(char::is_whitespace).unwrap_or(value.len()),
+        };
+        let raw_value = &value[..value_end];
+        rest = &value[value_end..];
+        let unquoted = raw_value.trim_matches(|c| c == '"' || c == '\'');
+        if is_safe_attr(attr_name, unquoted) {
+            out.push(' ');
+            out.push_str(attr_name);
+            out.push('=');
+            out.push_str(raw_value);
+        }
+    }
+    if inner.trim_end().ends_with('/') {
+        out.push('/');
+    }
+    out
+}
+
+fn is_event_handler(attr_name: &str) -> bool {
+    local_name(attr_name)
+        .get(..2)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("on"))
+}
+
+fn is_safe_attr(attr_name: &str, value: &str) -> bool {
+    if is_event_handler(attr_name) {
+        return false;
+    }
+    // browsers ignore whitespace and control characters inside URL schemes
+    let value: String = decode_char_refs(value)
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    if value.contains("javascript:") || value.contains("vbscript:") {
+        return false;
+    }
+    let local = local_name(attr_name).to_ascii_lowercase();
+    if matches!(local.as_str(), "href" | "src") && !is_internal_ref(&value) {
+        return false;
+    }
+    !has_external_url(&value)
+}
+
+/// Decodes numeric character references such as `&#58;` and `&#x3a;`, which XML expands
+/// in attribute values before they are interpreted.
+fn decode_char_refs(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("&#") {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest.find(';').and_then(|end| {
+            let digits = &rest[2..end];
+            let code = match digits.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => digits.parse().ok()?,
+            };
+            Some((char::from_u32(code)?, end + 1))
+        });
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push_str("&#");
+                rest = &rest[2..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Whether a reference stays within the document: a fragment or an inline raster image.
+fn is_internal_ref(value: &str) -> bool {
+    value.starts_with('#')
+        || (value.starts_with("data:image/") && !value.starts_with("data:image/svg+xml"))
+}
+
+/// Whether lowercased CSS or an attribute value has a `url(...)` leaving the document.
+fn has_external_url(value: &str) -> bool {
+    value.match_indices("url(").any(|(i, _)| {
+        let target =
+            value[i + 4..].trim_start_matches(|c: char| c.is_whitespace() || c == '"' || c == '\'');
+        !is_internal_ref(target)
+    })
+}
+
+/// Adds the headers every sanitized SVG is served with.
+pub(crate) fn apply_headers(headers: &Headers) -> Result<()> {
+    headers.set("Content-Security-Policy", CONTENT_SECURITY_POLICY)?;
+    headers.set("Content-Disposition", "inline")
+}
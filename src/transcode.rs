@@ -0,0 +1,128 @@
+use image::{codecs::webp::WebPEncoder, ImageFormat, RgbaImage};
+
+use crate::sniff;
+
+/// Origin bodies smaller than this are stored as-is; the savings would not pay for the
+/// decode.
+const MIN_TRANSCODE_BYTES: usize = 16 * 1024;
+
+/// `TRANSCODE_QUALITY` used when it is not set.
+pub(crate) const DEFAULT_QUALITY: u8 = 75;
+
+/// Re-encodes a JPEG or PNG `body` as WebP at `quality` (0 to 100, where 100 is
+/// lossless), returning `None` when it is of another format, animated, too small to
+/// bother, fails to decode, or would not get any smaller.
+pub(crate) fn to_webp(body: &[u8], quality: u8) -> Option<Vec<u8>> {
+    // decoding keeps only the first frame of an APNG
+    if body.len() < MIN_TRANSCODE_BYTES || sniff::is_animated(body) {
+        return None;
+    }
+    let format = image::guess_format(body).ok()?;
+    if !matches!(format, ImageFormat::Jpeg | ImageFormat::Png) {
+        return None;
+    }
+    let image = image::load_from_memory_with_format(body, format).ok()?;
+    let encoded = encode(image.to_rgba8(), quality)?;
+    (encoded.len() < body.len()).then_some(encoded)
+}
+
+/// Encodes `image` as WebP at `quality`.
+///
+/// `image-webp` only writes lossless VP8L, so below 100 the low bits of every colour
+/// channel are rounded away first, as libwebp's near-lossless mode does. The flattened
+/// gradients and noise are what lets photographs compress.
+fn encode(mut image: RgbaImage, quality: u8) -> Option<Vec<u8>> {
+    let bits = dropped_bits(quality);
+    if bits > 0 {
+        for pixel in image.pixels_mut() {
+            // alpha is left alone, as banding in it shows as halos
+            for channel in &mut pixel.0[..3] {
+                *channel = quantize(*channel, bits);
+            }
+        }
+    }
+    let mut encoded = Vec::new();
+    image
+        .write_with_encoder(WebPEncoder::new_lossless(&mut encoded))
+        .ok()?;
+    Some(encoded)
+}
+
+/// Low bits dropped from each channel at `quality`: none at 100, one just below it and one
+/// more every 20 below that, up to 4.
+fn dropped_bits(quality: u8) -> u32 {
+    (u32::from(100 - quality.min(100)) / 20 + u32::from(quality < 100)).min(4)
+}
+
+/// Rounds `value` to the nearest multiple of `2^bits` that fits in a byte.
+fn quantize(value: u8, bits: u32) -> u8 {
+    let step = 1u16 << bits;
+    let rounded = (u16::from(value) + step / 2) & !(step - 1);
+    rounded.min(255 & !(step - 1)) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A photograph-like image: a gradient with per-pixel noise.
+    fn noisy(size: u32) -> RgbaImage {
+        let mut seed = 1u32;
+        RgbaImage::from_fn(size, size, |x, y| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let noise = (seed >> 16) as u8 % 16;
+            let r = (x * 255 / size) as u8;
+            let g = (y * 255 / size) as u8;
+            image::Rgba([r.saturating_add(noise), g.saturating_add(noise), noise, 255])
+        })
+    }
+
+    #[test]
+    fn quality_maps_to_dropped_bits() {
+        assert_eq!(dropped_bits(100), 0);
+        assert_eq!(dropped_bits(255), 0);
+        assert_eq!(dropped_bits(99), 1);
+        assert_eq!(dropped_bits(81), 1);
+        assert_eq!(dropped_bits(80), 2);
+        assert_eq!(dropped_bits(75), 2);
+        assert_eq!(dropped_bits(40), 4);
+        assert_eq!(dropped_bits(0), 4);
+    }
+
+    #[test]
+    fn quantize_rounds_to_the_nearest_step() {
+        assert_eq!(quantize(0, 2), 0);
+        assert_eq!(quantize(1, 2), 0);
+        assert_eq!(quantize(2, 2), 4);
+        assert_eq!(quantize(130, 3), 128);
+        assert_eq!(quantize(255, 2), 252);
+        assert_eq!(quantize(77, 0), 77);
+    }
+
+    #[test]
+    fn lower_quality_is_smaller() {
+        let lossless = encode(noisy(128), 100).unwrap();
+        let lossy = encode(noisy(128), DEFAULT_QUALITY).unwrap();
+        assert!(
+            lossy.len() < lossless.len(),
+            "{} >= {}",
+            lossy.len(),
+            lossless.len()
+        );
+    }
+
+    #[test]
+    fn transparency_is_kept() {
+        let mut image = noisy(8);
+        image.put_pixel(0, 0, image::Rgba([10, 20, 30, 7]));
+        let encoded = encode(image, 0).unwrap();
+        let decoded = image::load_from_memory(&encoded).unwrap().to_rgba8();
+        assert_eq!(decoded.get_pixel(0, 0).0[3], 7);
+    }
+
+    #[test]
+    fn small_and_non_image_bodies_are_skipped() {
+        assert_eq!(to_webp(b"not an image", DEFAULT_QUALITY), None);
+        assert_eq!(to_webp(&[0; MIN_TRANSCODE_BYTES], DEFAULT_QUALITY), None);
+    }
+}